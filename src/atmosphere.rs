@@ -0,0 +1,330 @@
+use crate::texture;
+
+// Must match TRANSMITTANCE_LUT_SIZE and SKY_VIEW_LUT_SIZE in atmosphere_common.wgsl.
+pub const TRANSMITTANCE_LUT_SIZE: (u32, u32) = (256, 64);
+pub const SKY_VIEW_LUT_SIZE: (u32, u32) = (192, 108);
+
+const LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Rayleigh + Mie atmosphere of an Earth-like planet. Distances are in kilometers and
+/// coefficients are per kilometer, following Hillaire, "A Scalable and Production Ready
+/// Sky and Atmosphere Rendering Technique", 2020.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct AtmosphereUniform {
+    /// Direction towards the sun.
+    pub sun_direction: [f32; 3],
+    pub sun_illuminance: f32,
+    pub rayleigh_scattering: [f32; 3],
+    pub rayleigh_scale_height: f32,
+    pub mie_scattering: f32,
+    pub mie_absorption: f32,
+    pub mie_scale_height: f32,
+    /// Eccentricity of the Mie phase function.
+    pub mie_g: f32,
+    pub ozone_absorption: [f32; 3],
+    pub camera_altitude: f32,
+    pub bottom_radius: f32,
+    pub top_radius: f32,
+    _padding: [f32; 2],
+}
+
+impl AtmosphereUniform {
+    pub fn new() -> Self {
+        Self {
+            sun_direction: [0.0, 1.0, 0.0],
+            sun_illuminance: 10.0,
+            rayleigh_scattering: [5.802e-3, 13.558e-3, 33.1e-3],
+            rayleigh_scale_height: 8.0,
+            mie_scattering: 3.996e-3,
+            mie_absorption: 4.4e-4,
+            mie_scale_height: 1.2,
+            mie_g: 0.8,
+            ozone_absorption: [0.65e-3, 1.881e-3, 0.085e-3],
+            camera_altitude: 1.0,
+            bottom_radius: 6360.0,
+            top_radius: 6460.0,
+            ..Default::default()
+        }
+    }
+}
+
+/// Owns the lookup tables of the atmosphere. The transmittance LUT is rendered once, while
+/// the sky-view LUT depends on the sun and the camera and is rendered every frame.
+pub struct Atmosphere {
+    pub uniform: AtmosphereUniform,
+    buffer: wgpu::Buffer,
+    sky_view_pipeline: wgpu::RenderPipeline,
+    sky_view_lut_view: wgpu::TextureView,
+    sky_view_bind_group: wgpu::BindGroup,
+    /// Layout of `bind_group`, for the pipelines sampling the atmosphere.
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    /// Binds the uniform, the transmittance LUT, the sky-view LUT and a linear sampler.
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl Atmosphere {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, uniform: AtmosphereUniform) -> Self {
+        use wgpu::util::DeviceExt;
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Atmosphere Shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                concat!(
+                    include_str!("shaders/atmosphere_common.wgsl"),
+                    include_str!("shaders/atmosphere.wgsl")
+                )
+                .into(),
+            ),
+        });
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Atmosphere Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let transmittance_lut_view = texture::create_render_target(
+            device,
+            TRANSMITTANCE_LUT_SIZE.0,
+            TRANSMITTANCE_LUT_SIZE.1,
+            LUT_FORMAT,
+            "Transmittance LUT",
+        )
+        .create_view(&Default::default());
+
+        let sky_view_lut_view = texture::create_render_target(
+            device,
+            SKY_VIEW_LUT_SIZE.0,
+            SKY_VIEW_LUT_SIZE.1,
+            LUT_FORMAT,
+            "Sky-View LUT",
+        )
+        .create_view(&Default::default());
+
+        let uniform_entry = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let lut_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        };
+
+        let transmittance_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[uniform_entry],
+                label: Some("transmittance_lut_bind_group_layout"),
+            });
+
+        let transmittance_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &transmittance_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("transmittance_lut_bind_group"),
+        });
+
+        let sky_view_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[uniform_entry, lut_entry(1), sampler_entry(2)],
+                label: Some("sky_view_lut_bind_group_layout"),
+            });
+
+        let sky_view_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &sky_view_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&transmittance_lut_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("sky_view_lut_bind_group"),
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[uniform_entry, lut_entry(1), lut_entry(2), sampler_entry(3)],
+            label: Some("atmosphere_bind_group_layout"),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&transmittance_lut_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&sky_view_lut_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("atmosphere_bind_group"),
+        });
+
+        let transmittance_pipeline = create_lut_pipeline(
+            device,
+            &shader,
+            &transmittance_bind_group_layout,
+            "fs_transmittance_lut",
+        );
+        let sky_view_pipeline = create_lut_pipeline(
+            device,
+            &shader,
+            &sky_view_bind_group_layout,
+            "fs_sky_view_lut",
+        );
+
+        // The transmittance only depends on the atmosphere itself, so it is rendered once.
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Transmittance LUT Encoder"),
+        });
+        render_lut(
+            &mut encoder,
+            &transmittance_pipeline,
+            &transmittance_bind_group,
+            &transmittance_lut_view,
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Self {
+            uniform,
+            buffer,
+            sky_view_pipeline,
+            sky_view_lut_view,
+            sky_view_bind_group,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    /// Uploads the uniform after the sun or the camera moved.
+    pub fn write_uniform(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    pub fn render_sky_view_lut(&self, encoder: &mut wgpu::CommandEncoder) {
+        render_lut(
+            encoder,
+            &self.sky_view_pipeline,
+            &self.sky_view_bind_group,
+            &self.sky_view_lut_view,
+        );
+    }
+}
+
+fn create_lut_pipeline(
+    device: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    bind_group_layout: &wgpu::BindGroupLayout,
+    fragment_entry_point: &str,
+) -> wgpu::RenderPipeline {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("LUT Pipeline Layout"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(fragment_entry_point),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: Some("vs_main"),
+            buffers: &[],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: Some(fragment_entry_point),
+            targets: &[Some(wgpu::ColorTargetState {
+                format: LUT_FORMAT,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            ..Default::default()
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+        cache: None,
+    })
+}
+
+fn render_lut(
+    encoder: &mut wgpu::CommandEncoder,
+    pipeline: &wgpu::RenderPipeline,
+    bind_group: &wgpu::BindGroup,
+    target: &wgpu::TextureView,
+) {
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("LUT Render Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: target,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                store: wgpu::StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    });
+
+    render_pass.set_pipeline(pipeline);
+    render_pass.set_bind_group(0, bind_group, &[]);
+    // No vertex buffer. The vertices are hardcoded in the vertex shader.
+    render_pass.draw(0..6, 0..1);
+}
@@ -0,0 +1,257 @@
+use crate::models::CloudVolume;
+
+/// Marks an interior node in [`BvhNode::volume`].
+const INTERIOR: u32 = u32::MAX;
+// Must match BVH_STACK_SIZE in raymarch.wgsl.
+const BVH_STACK_SIZE: u32 = 32;
+
+/// The root of a hierarchy without volumes, an interior node without children. Children
+/// always come after their parent, so no other node has `first_child` 0.
+const EMPTY_ROOT: BvhNode = BvhNode {
+    min: [0.0; 3],
+    first_child: 0,
+    max: [0.0; 3],
+    volume: INTERIOR,
+};
+
+/// A node of the bounding volume hierarchy, as stored in the BVH storage buffer. A leaf holds
+/// a single cloud volume, and an interior node has two children next to each other.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct BvhNode {
+    /// World space bounds of everything below the node.
+    min: [f32; 3],
+    /// Index of the first child of an interior node. The second child comes right after it.
+    first_child: u32,
+    max: [f32; 3],
+    /// Index of the cloud volume in a leaf, or [`INTERIOR`].
+    volume: u32,
+}
+
+/// A bounding volume hierarchy over the cloud volumes, which lets the raymarcher find the
+/// volumes along a ray without testing every one of them. The root is the first node, and
+/// children always come after their parent.
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+}
+
+impl Bvh {
+    /// Builds the hierarchy by splitting the volumes in half along the longest axis of their
+    /// centers, until every leaf holds one volume. Without volumes, the hierarchy is just a
+    /// root without children, as the storage buffer can't be empty. The shader skips it.
+    ///
+    /// Panics if the hierarchy is too deep for the shader's traversal stack, which halving
+    /// the volumes at every level only reaches with more than 2³¹ of them.
+    pub fn build(volumes: &[CloudVolume]) -> Self {
+        if volumes.is_empty() {
+            return Self {
+                nodes: vec![EMPTY_ROOT],
+            };
+        }
+
+        let bounds: Vec<_> = volumes.iter().map(CloudVolume::bounds).collect();
+        let mut indices: Vec<u32> = (0..volumes.len() as u32).collect();
+
+        let mut nodes = Vec::with_capacity(2 * volumes.len() - 1);
+        nodes.push(BvhNode::default());
+        let depth = subdivide(&mut nodes, 0, &mut indices, &bounds);
+        // The traversal holds at most one node more than the depth on its stack.
+        assert!(
+            depth < BVH_STACK_SIZE,
+            "A BVH of depth {depth} overflows the traversal stack of {BVH_STACK_SIZE} nodes"
+        );
+
+        Self { nodes }
+    }
+
+    /// Updates the bounds of the nodes to where the volumes are now, keeping the structure of
+    /// the hierarchy. This is much cheaper than building it again, but the hierarchy gets
+    /// worse the farther the volumes move from where it was built.
+    pub fn refit(&mut self, volumes: &[CloudVolume]) {
+        if volumes.is_empty() {
+            return;
+        }
+
+        // Children come after their parent, so going backwards visits them first.
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            let (min, max) = if node.volume == INTERIOR {
+                let first = self.nodes[node.first_child as usize];
+                let second = self.nodes[node.first_child as usize + 1];
+                union((first.min, first.max), (second.min, second.max))
+            } else {
+                volumes[node.volume as usize].bounds()
+            };
+            self.nodes[i].min = min;
+            self.nodes[i].max = max;
+        }
+    }
+}
+
+/// Fills in `node` with the volumes at `indices`, and returns the depth of the subtree.
+fn subdivide(
+    nodes: &mut Vec<BvhNode>,
+    node: usize,
+    indices: &mut [u32],
+    bounds: &[([f32; 3], [f32; 3])],
+) -> u32 {
+    if let [volume] = *indices {
+        let (min, max) = bounds[volume as usize];
+        nodes[node] = BvhNode {
+            min,
+            first_child: 0,
+            max,
+            volume,
+        };
+        return 0;
+    }
+
+    let center = |index: u32| {
+        let (min, max) = bounds[index as usize];
+        [0, 1, 2].map(|axis| (min[axis] + max[axis]) * 0.5)
+    };
+    let (center_min, center_max) = indices
+        .iter()
+        .map(|&index| (center(index), center(index)))
+        .reduce(union)
+        .unwrap();
+    let axis = (0..3)
+        .max_by(|&a, &b| {
+            let extent = |axis: usize| center_max[axis] - center_min[axis];
+            extent(a).total_cmp(&extent(b))
+        })
+        .unwrap();
+
+    indices.sort_by(|&a, &b| center(a)[axis].total_cmp(&center(b)[axis]));
+    let (first, second) = indices.split_at_mut(indices.len() / 2);
+
+    let first_child = nodes.len();
+    nodes.push(BvhNode::default());
+    nodes.push(BvhNode::default());
+    let first_depth = subdivide(nodes, first_child, first, bounds);
+    let second_depth = subdivide(nodes, first_child + 1, second, bounds);
+
+    let first_node = nodes[first_child];
+    let second_node = nodes[first_child + 1];
+    let (min, max) = union(
+        (first_node.min, first_node.max),
+        (second_node.min, second_node.max),
+    );
+    nodes[node] = BvhNode {
+        min,
+        first_child: first_child as u32,
+        max,
+        volume: INTERIOR,
+    };
+    first_depth.max(second_depth) + 1
+}
+
+fn union(a: ([f32; 3], [f32; 3]), b: ([f32; 3], [f32; 3])) -> ([f32; 3], [f32; 3]) {
+    (
+        [0, 1, 2].map(|axis| a.0[axis].min(b.0[axis])),
+        [0, 1, 2].map(|axis| a.1[axis].max(b.1[axis])),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Quaternion, Rotation3, Vector3};
+
+    /// Boxes of different sizes and rotations, scattered unevenly so that the splits go along
+    /// different axes.
+    fn volumes(count: usize) -> Vec<CloudVolume> {
+        (0..count)
+            .map(|i| {
+                let i = i as f32;
+                CloudVolume::new(
+                    Vector3::new((i * 2.3) % 7.0, (i * 1.7) % 3.0, -i * 0.9),
+                    Quaternion::from_angle_y(Deg(i * 25.0)),
+                    Vector3::new(1.0 + i * 0.1, 0.5, 0.8),
+                    0,
+                )
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn contains(outer: &BvhNode, inner: &BvhNode) -> bool {
+        (0..3).all(|axis| outer.min[axis] <= inner.min[axis] && inner.max[axis] <= outer.max[axis])
+    }
+
+    #[test]
+    fn every_volume_is_in_one_leaf() {
+        let volumes = volumes(9);
+        let bvh = Bvh::build(&volumes);
+
+        let mut leaves: Vec<_> = bvh
+            .nodes
+            .iter()
+            .filter(|node| node.volume != INTERIOR)
+            .map(|node| node.volume)
+            .collect();
+        leaves.sort_unstable();
+        assert_eq!(leaves, (0..volumes.len() as u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parents_contain_their_children() {
+        let bvh = Bvh::build(&volumes(9));
+
+        for node in bvh.nodes.iter().filter(|node| node.volume == INTERIOR) {
+            let first_child = node.first_child as usize;
+            assert!(contains(node, &bvh.nodes[first_child]));
+            assert!(contains(node, &bvh.nodes[first_child + 1]));
+        }
+    }
+
+    #[test]
+    fn has_two_nodes_per_volume_but_one() {
+        for count in 1..=9 {
+            assert_eq!(Bvh::build(&volumes(count)).nodes.len(), 2 * count - 1);
+        }
+    }
+
+    #[test]
+    fn refit_matches_a_new_build() {
+        let mut volumes = volumes(9);
+        let mut bvh = Bvh::build(&volumes);
+        for (i, volume) in volumes.iter_mut().enumerate() {
+            volume.translate(Vector3::new(0.5, -0.2, i as f32 * 0.3));
+        }
+        bvh.refit(&volumes);
+
+        let built = Bvh::build(&volumes);
+        assert_eq!(bvh.nodes[0].min, built.nodes[0].min);
+        assert_eq!(bvh.nodes[0].max, built.nodes[0].max);
+        for node in &bvh.nodes {
+            if node.volume == INTERIOR {
+                let first_child = node.first_child as usize;
+                assert!(contains(node, &bvh.nodes[first_child]));
+                assert!(contains(node, &bvh.nodes[first_child + 1]));
+            } else {
+                assert_eq!((node.min, node.max), volumes[node.volume as usize].bounds());
+            }
+        }
+    }
+
+    #[test]
+    fn single_volume_is_a_leaf_root() {
+        let volumes = volumes(1);
+        let bvh = Bvh::build(&volumes);
+
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].volume, 0);
+        assert_eq!((bvh.nodes[0].min, bvh.nodes[0].max), volumes[0].bounds());
+    }
+
+    #[test]
+    fn no_volumes_is_a_root_without_children() {
+        let mut bvh = Bvh::build(&[]);
+        bvh.refit(&[]);
+
+        assert_eq!(bvh.nodes.len(), 1);
+        assert_eq!(bvh.nodes[0].volume, INTERIOR);
+        assert_eq!(bvh.nodes[0].first_child, 0);
+    }
+}
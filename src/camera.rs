@@ -27,7 +27,7 @@ impl Camera {
     pub fn build_view_projection_matrix(&self) -> cgmath::Matrix4<f32> {
         let view = cgmath::Matrix4::look_to_rh(self.eye, self.direction, self.up);
         let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
-        return OPENGL_TO_WGPU_MATRIX * proj * view;
+        OPENGL_TO_WGPU_MATRIX * proj * view
     }
 }
 
@@ -185,6 +185,9 @@ pub struct CameraUniform {
     // We can't use cgmath with bytemuck directly, so we'll have
     // to convert the Matrix4 into a 4x4 f32 array
     view_proj_inv: [[f32; 4]; 4],
+    // The view projection of the previous frame, to reproject the cloud history.
+    prev_view_proj: [[f32; 4]; 4],
+    view_proj: [[f32; 4]; 4],
     cam_pos: [f32; 3],
     _padding: f32,
 }
@@ -194,17 +197,18 @@ impl CameraUniform {
         use cgmath::SquareMatrix;
         Self {
             view_proj_inv: cgmath::Matrix4::identity().into(),
+            prev_view_proj: cgmath::Matrix4::identity().into(),
+            view_proj: cgmath::Matrix4::identity().into(),
             cam_pos: [0.0, 0.0, 0.0],
             ..Default::default()
         }
     }
 
     pub fn update(&mut self, camera: &Camera) {
-        self.view_proj_inv = camera
-            .build_view_projection_matrix()
-            .invert()
-            .unwrap()
-            .into();
+        let view_proj = camera.build_view_projection_matrix();
+        self.prev_view_proj = self.view_proj;
+        self.view_proj = view_proj.into();
+        self.view_proj_inv = view_proj.invert().unwrap().into();
         self.cam_pos = camera.eye.into();
     }
 }
@@ -0,0 +1,336 @@
+use cgmath::{InnerSpace, Point3, Vector3};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct CloudUniform {
+    /// How many times the detail noise repeats across one tile of the base shape noise.
+    pub detail_scale: f32,
+    /// How much the detail noise erodes the edges of the base shape, in [0, 1].
+    pub erosion_strength: f32,
+    /// Global multiplier on top of the density channel of the weather map.
+    pub density_multiplier: f32,
+    /// World space size of one tile of the base shape noise. The noise textures repeat, so
+    /// they cover volumes of any size.
+    pub noise_scale: f32,
+}
+
+impl CloudUniform {
+    pub fn new() -> Self {
+        Self {
+            detail_scale: 4.0,
+            erosion_strength: 0.35,
+            density_multiplier: 3.0,
+            noise_scale: 1.0,
+        }
+    }
+}
+
+/// Vertical density profiles of the cloud types. Each profile is
+/// `[bottom_start, bottom_end, top_start, top_end]` in normalized height inside the volume:
+/// the density ramps up from `bottom_start` to `bottom_end` and back down from `top_start` to
+/// `top_end`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct CloudTypeUniform {
+    pub stratus: [f32; 4],
+    pub cumulus: [f32; 4],
+    pub cumulonimbus: [f32; 4],
+    /// Cloud type in [0, 1] used for every pixel, or negative to read it from the weather map.
+    pub type_override: f32,
+    /// How much the coverage of cumulonimbus spreads out at the top to form an anvil, in [0, 1].
+    pub anvil_bias: f32,
+    _padding: [f32; 2],
+}
+
+impl CloudTypeUniform {
+    pub fn new() -> Self {
+        Self {
+            stratus: [0.0, 0.05, 0.1, 0.25],
+            cumulus: [0.02, 0.2, 0.48, 0.65],
+            cumulonimbus: [0.0, 0.1, 0.8, 1.0],
+            type_override: -1.0,
+            anvil_bias: 0.5,
+            ..Default::default()
+        }
+    }
+}
+
+/// Parameters of the octave based multiple scattering approximation. Octave `i` scales
+/// extinction, scattering and phase eccentricity by `a^i`, `b^i` and `c^i` respectively.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct ScatteringUniform {
+    /// 0 for single scattering only, anything else to enable multiple scattering.
+    pub multiple_scattering: u32,
+    pub octaves: u32,
+    /// `a`, should be less than or equal to `b` to conserve energy.
+    pub extinction_attenuation: f32,
+    /// `b`
+    pub scattering_attenuation: f32,
+    /// `c`
+    pub eccentricity_attenuation: f32,
+    /// One of [`PhaseFunction`] as `u32`.
+    pub phase_function: u32,
+    /// Eccentricity of the forward lobe, also used by the single lobe phase functions.
+    pub g_forward: f32,
+    /// Eccentricity of the backward lobe of the dual-lobe Henyey-Greenstein.
+    pub g_back: f32,
+    /// Weight of the forward lobe of the dual-lobe Henyey-Greenstein, in [0, 1].
+    pub lobe_blend: f32,
+    /// Water droplet diameter in micrometers for the Henyey-Greenstein + Draine fit, in [5, 50].
+    pub droplet_diameter: f32,
+    /// How much the powder effect darkens the thin edges of the clouds, in [0, 1]. 0 disables it.
+    pub powder_strength: f32,
+    /// In [0, 1]. At 0 the powder effect is the same from every angle, at 1 it only shows on
+    /// the side of the clouds facing the light, i.e. with the light behind the viewer.
+    pub powder_angle_dependence: f32,
+}
+
+impl ScatteringUniform {
+    pub fn new() -> Self {
+        Self {
+            multiple_scattering: 1,
+            octaves: 4,
+            extinction_attenuation: 0.5,
+            scattering_attenuation: 0.5,
+            eccentricity_attenuation: 0.5,
+            phase_function: PhaseFunction::DualLobeHenyeyGreenstein as u32,
+            g_forward: 0.6,
+            g_back: -0.3,
+            lobe_blend: 0.8,
+            droplet_diameter: 20.0,
+            powder_strength: 0.8,
+            powder_angle_dependence: 0.5,
+        }
+    }
+
+    pub fn toggle_multiple_scattering(&mut self) {
+        self.multiple_scattering = (self.multiple_scattering == 0) as u32;
+    }
+
+    pub fn cycle_phase_function(&mut self) -> PhaseFunction {
+        let next = (self.phase_function as usize + 1) % PhaseFunction::ALL.len();
+        self.phase_function = next as u32;
+        PhaseFunction::ALL[next]
+    }
+}
+
+/// Coefficients of the participating medium at a density of 1, per unit of distance.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct MediumUniform {
+    pub scattering: [f32; 3],
+    _padding1: f32,
+    pub absorption: [f32; 3],
+    _padding2: f32,
+}
+
+impl MediumUniform {
+    pub fn new(scattering: [f32; 3], absorption: [f32; 3]) -> Self {
+        Self {
+            scattering,
+            absorption,
+            ..Default::default()
+        }
+    }
+
+    /// Splits the extinction coefficient into scattering and absorption, where `albedo`
+    /// is the ratio of scattering to extinction.
+    pub fn from_albedo(extinction: [f32; 3], albedo: [f32; 3]) -> Self {
+        let scattering = [0, 1, 2].map(|i| extinction[i] * albedo[i]);
+        let absorption = [0, 1, 2].map(|i| extinction[i] * (1.0 - albedo[i]));
+        Self::new(scattering, absorption)
+    }
+}
+
+/// Step sizes of the raymarching. They are fractions of the smallest extent of the cloud
+/// volume being marched through, so the quality doesn't depend on its world-space size.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct MarchUniform {
+    /// Step size right in front of the camera.
+    pub min_step: f32,
+    pub max_step: f32,
+    /// How much the step size grows per volume size of distance from the camera.
+    pub step_growth: f32,
+    /// Step multiplier while searching for clouds. The march goes back one coarse step and
+    /// continues with fine steps once it finds one. The step back is clamped to fewer fine
+    /// steps than it takes to switch to coarse steps, so larger values only search faster.
+    pub coarse_step_scale: f32,
+    /// Maximum number of density samples along a camera ray.
+    pub max_samples: u32,
+    /// First step size towards the lights.
+    pub light_step: f32,
+    /// Maximum number of density samples towards a light. Together with `light_step` and
+    /// `light_step_growth`, it also limits how far away clouds cast shadows.
+    pub light_max_samples: u32,
+    /// How much larger every step towards a light is than the one before. The shadowing
+    /// right next to a sample matters the most, and farther clouds only darken it overall.
+    pub light_step_growth: f32,
+}
+
+impl MarchUniform {
+    pub fn new() -> Self {
+        Self {
+            min_step: 0.02,
+            max_step: 0.1,
+            step_growth: 0.015,
+            coarse_step_scale: 2.0,
+            max_samples: 256,
+            light_step: 0.1,
+            light_max_samples: 16,
+            light_step_growth: 1.1,
+        }
+    }
+}
+
+/// The region of space the clouds are in.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct VolumeUniform {
+    /// One of [`VolumeMode`] as `u32`.
+    pub mode: u32,
+    /// Altitude of the bottom of the layer or shell. Not used by [`VolumeMode::Box`].
+    pub layer_bottom: f32,
+    /// Altitude of the top of the layer or shell. Not used by [`VolumeMode::Box`].
+    pub layer_top: f32,
+    /// How far from the camera the layer is marched, as it never ends horizontally. Only used
+    /// by [`VolumeMode::Layer`], the shell ends at the horizon.
+    pub max_distance: f32,
+    /// World space size of one tile of the weather map in the layer or shell. Every box is
+    /// always covered by exactly one tile.
+    pub weather_scale: f32,
+    /// Radius of the planet the shell is around. Its center is right below the origin, so
+    /// that its surface is at an altitude of 0.
+    pub planet_radius: f32,
+    /// Altitude of the camera above the planet, set by [`VolumeUniform::update_camera`].
+    pub camera_altitude: f32,
+    /// Distance from the center of the planet to the camera, set by
+    /// [`VolumeUniform::update_camera`].
+    pub camera_radius: f32,
+}
+
+impl VolumeUniform {
+    pub fn new() -> Self {
+        Self {
+            mode: VolumeMode::Box as u32,
+            layer_bottom: 2.0,
+            layer_top: 3.0,
+            max_distance: 30.0,
+            weather_scale: 10.0,
+            planet_radius: 6360.0,
+            ..Default::default()
+        }
+    }
+
+    /// Finds the altitude of the camera in double precision, as in single precision it would
+    /// be the difference of two distances in the thousands. The shader finds the altitudes in
+    /// the shell relative to it.
+    pub fn update_camera(&mut self, eye: Point3<f32>) {
+        let planet_radius = f64::from(self.planet_radius);
+        let camera_radius = Vector3::new(
+            f64::from(eye.x),
+            f64::from(eye.y) + planet_radius,
+            f64::from(eye.z),
+        )
+        .magnitude();
+        self.camera_altitude = (camera_radius - planet_radius) as f32;
+        self.camera_radius = camera_radius as f32;
+    }
+
+    pub fn cycle_mode(&mut self) -> VolumeMode {
+        let next = (self.mode as usize + 1) % VolumeMode::ALL.len();
+        self.mode = next as u32;
+        VolumeMode::ALL[next]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VolumeMode {
+    /// Inside the boxes of the [`crate::models::CloudVolume`]s.
+    Box = 0,
+    /// Between `layer_bottom` and `layer_top`, without horizontal bounds.
+    Layer = 1,
+    /// Between `layer_bottom` and `layer_top` above a planet of `planet_radius`, so the
+    /// clouds follow its curvature.
+    Shell = 2,
+}
+
+impl VolumeMode {
+    pub const ALL: [VolumeMode; 3] = [VolumeMode::Box, VolumeMode::Layer, VolumeMode::Shell];
+}
+
+/// Light scattered into the clouds from the whole sky rather than from a single light, so
+/// that the parts in shadow of every light don't turn black.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct AmbientUniform {
+    /// Ambient color at the top of the volume. Not used by [`AmbientMode::Sky`].
+    pub top_color: [f32; 3],
+    /// One of [`AmbientMode`] as `u32`.
+    pub mode: u32,
+    /// Ambient color at the bottom of the volume. Not used by [`AmbientMode::Constant`].
+    pub bottom_color: [f32; 3],
+    pub intensity: f32,
+    /// How much less ambient light reaches the bottom of the volume, which is occluded by the
+    /// cloud above it, in [0, 1].
+    pub occlusion_strength: f32,
+    _padding: [f32; 3],
+}
+
+impl AmbientUniform {
+    pub fn new() -> Self {
+        Self {
+            top_color: [0.45, 0.6, 0.85],
+            mode: AmbientMode::Sky as u32,
+            bottom_color: [0.15, 0.15, 0.18],
+            intensity: 1.0,
+            occlusion_strength: 0.6,
+            ..Default::default()
+        }
+    }
+
+    pub fn cycle_mode(&mut self) -> AmbientMode {
+        let next = (self.mode as usize + 1) % AmbientMode::ALL.len();
+        self.mode = next as u32;
+        AmbientMode::ALL[next]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AmbientMode {
+    /// `top_color` everywhere.
+    Constant = 0,
+    /// Blend from `bottom_color` to `top_color` with the height in the volume.
+    HeightGradient = 1,
+    /// Like `HeightGradient`, with the top color integrated from the atmosphere.
+    Sky = 2,
+}
+
+impl AmbientMode {
+    pub const ALL: [AmbientMode; 3] = [
+        AmbientMode::Constant,
+        AmbientMode::HeightGradient,
+        AmbientMode::Sky,
+    ];
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PhaseFunction {
+    HenyeyGreenstein = 0,
+    /// Blend of a forward and a backward Henyey-Greenstein lobe.
+    DualLobeHenyeyGreenstein = 1,
+    CornetteShanks = 2,
+    /// Fit of the Mie phase function of water droplets, see Jendersie and d'Eon,
+    /// "An Approximate Mie Scattering Function for Fog and Cloud Rendering", 2023.
+    HenyeyGreensteinDraine = 3,
+}
+
+impl PhaseFunction {
+    pub const ALL: [PhaseFunction; 4] = [
+        PhaseFunction::HenyeyGreenstein,
+        PhaseFunction::DualLobeHenyeyGreenstein,
+        PhaseFunction::CornetteShanks,
+        PhaseFunction::HenyeyGreensteinDraine,
+    ];
+}
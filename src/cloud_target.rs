@@ -0,0 +1,136 @@
+use crate::texture;
+
+/// Premultiplied color and alpha of the clouds.
+pub const COLOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+/// Distance to the first surface along each ray, which guides the upsampling.
+/// `R32Float` can't be rendered to on GL.
+pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R16Float;
+
+/// The fractions of the screen size the clouds can be rendered at.
+pub const RENDER_SCALES: [f32; 3] = [1.0, 0.5, 0.25];
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ScreenUniform {
+    pub size: [u32; 2],
+    /// Size of the [`CloudTarget`].
+    pub cloud_size: [u32; 2],
+}
+
+impl ScreenUniform {
+    pub fn new(width: u32, height: u32, render_scale: f32) -> Self {
+        Self {
+            size: [width, height],
+            cloud_size: scaled_size(width, height, render_scale),
+        }
+    }
+}
+
+fn scaled_size(width: u32, height: u32, render_scale: f32) -> [u32; 2] {
+    [width, height].map(|size| ((size as f32 * render_scale).ceil() as u32).max(1))
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct TemporalUniform {
+    /// Weight of the reprojected history when blended with the current frame, in [0, 1).
+    /// Higher values remove more noise, but take longer to converge after a change.
+    pub history_weight: f32,
+    /// 0 to only use the current frame.
+    pub enabled: u32,
+    /// 0 when there is no history yet, e.g. right after the cloud target was recreated.
+    pub history_valid: u32,
+    /// Index of the current frame, which animates the jittering of the rays.
+    pub frame: u32,
+    /// How far the clouds moved since the previous frame, which is undone when reprojecting
+    /// them into the history.
+    pub cloud_motion: [f32; 3],
+    _padding: f32,
+}
+
+impl TemporalUniform {
+    pub fn new() -> Self {
+        Self {
+            history_weight: 0.9,
+            enabled: 1,
+            ..Default::default()
+        }
+    }
+}
+
+/// Offscreen targets the clouds are raymarched into at a fraction of the screen size. Each
+/// frame is accumulated into one of two history textures, reading the other one, before
+/// the result is upsampled to the screen.
+pub struct CloudTarget {
+    pub color_view: wgpu::TextureView,
+    pub depth_view: wgpu::TextureView,
+    pub history_views: [wgpu::TextureView; 2],
+    /// `bind_groups[i]` binds the color, the depth and `history_views[i]`.
+    pub bind_groups: [wgpu::BindGroup; 2],
+}
+
+impl CloudTarget {
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            },
+            count: None,
+        };
+
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[entry(0), entry(1), entry(2)],
+            label: Some("cloud_target_bind_group_layout"),
+        })
+    }
+
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        screen_uniform: &ScreenUniform,
+    ) -> Self {
+        let [width, height] = screen_uniform.cloud_size;
+        let create_view = |format, label| {
+            texture::create_render_target(device, width, height, format, label)
+                .create_view(&Default::default())
+        };
+        let color_view = create_view(COLOR_FORMAT, "Cloud Color");
+        let depth_view = create_view(DEPTH_FORMAT, "Cloud Depth");
+        let history_views = [
+            create_view(COLOR_FORMAT, "Cloud History 0"),
+            create_view(COLOR_FORMAT, "Cloud History 1"),
+        ];
+
+        let bind_groups = [0, 1].map(|i| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&color_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&depth_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&history_views[i]),
+                    },
+                ],
+                label: Some("cloud_target_bind_group"),
+            })
+        });
+
+        Self {
+            color_view,
+            depth_view,
+            history_views,
+            bind_groups,
+        }
+    }
+}
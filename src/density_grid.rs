@@ -0,0 +1,158 @@
+use crate::texture;
+
+// Must match DENSITY_GRID_SIZE in raymarch.wgsl.
+pub const DENSITY_GRID_SIZE: u32 = 16;
+// Must match DENSITY_GRIDS_PER_COLUMN in raymarch.wgsl. Keeps the texture within 2048 texels,
+// the smallest maximum size of a texture on GL ES 3.0 and WebGL 2.
+const DENSITY_GRIDS_PER_COLUMN: u32 = 128;
+
+/// Upper bound of the density in every cell, as a 32 bit float isn't renderable on GL.
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R16Float;
+
+/// A coarse grid over every cloud volume holding the maximum density in every cell, which lets
+/// the raymarcher jump over empty cells and take larger steps in sparse ones. The grid is
+/// rendered on the GPU with the same `volume_density` the raymarcher uses, so the two never
+/// disagree about where the clouds are.
+pub struct DensityGrid {
+    pipeline: wgpu::RenderPipeline,
+    view: wgpu::TextureView,
+    /// Layout of `bind_group`, which is group 3 of the cloud raymarching pipeline.
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl DensityGrid {
+    /// `shader` is the raymarching shader, and the layouts are those of its groups 0 and 1.
+    /// `volume_count` is the number of cloud volumes, each of which gets its own grid.
+    pub fn new(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        uniform_bind_group_layout: &wgpu::BindGroupLayout,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+        volume_count: u32,
+    ) -> Self {
+        // Every 3D grid is laid out in a 2D texture as a row of horizontal slices, and the rows
+        // of the volumes are stacked on top of each other in columns.
+        // The raymarcher doesn't skip any space in the volumes whose grids don't fit.
+        let mut columns = volume_count.div_ceil(DENSITY_GRIDS_PER_COLUMN);
+        let max_columns =
+            device.limits().max_texture_dimension_2d / (DENSITY_GRID_SIZE * DENSITY_GRID_SIZE);
+        if columns > max_columns {
+            columns = max_columns;
+            log::warn!(
+                "Only the first {} of {volume_count} cloud volumes fit in the density grid \
+                 texture, empty space in the others isn't skipped",
+                columns * DENSITY_GRIDS_PER_COLUMN
+            );
+        }
+        let width = DENSITY_GRID_SIZE * DENSITY_GRID_SIZE * columns;
+        let height = DENSITY_GRID_SIZE * volume_count.min(DENSITY_GRIDS_PER_COLUMN);
+        let view = texture::create_render_target(device, width, height, FORMAT, "Density Grid")
+            .create_view(&Default::default());
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                },
+                count: None,
+            }],
+            label: Some("density_grid_bind_group_layout"),
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&view),
+            }],
+            label: Some("density_grid_bind_group"),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Density Grid Pipeline Layout"),
+            bind_group_layouts: &[uniform_bind_group_layout, texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Density Grid Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: Some("vs_main"),
+                buffers: &[],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: Some("fs_density_grid"),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: FORMAT,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            pipeline,
+            view,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    /// Renders the grid. Has to be called again whenever the density of the clouds changes.
+    pub fn update(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        uniform_bind_group: &wgpu::BindGroup,
+        texture_bind_group: &wgpu::BindGroup,
+    ) {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Density Grid Encoder"),
+        });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Density Grid Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, uniform_bind_group, &[]);
+            render_pass.set_bind_group(1, texture_bind_group, &[]);
+            // No vertex buffer. The vertices are hardcoded in the vertex shader.
+            render_pass.draw(0..6, 0..1);
+        }
+
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+}
@@ -1,11 +1,17 @@
+mod atmosphere;
+mod bvh;
 mod camera;
+mod cloud;
+mod cloud_target;
+mod density_grid;
+mod light;
 mod models;
 mod texture;
 
-use std::path::Path;
+use std::path::PathBuf;
 
 use camera::{CameraController, CameraUniform};
-use cgmath::{Angle, Rad};
+use cgmath::{Angle, Deg, InnerSpace, One, Quaternion, Rad, Rotation3, Vector3};
 use wgpu::{TextureView, util::DeviceExt};
 use winit::{
     event::*,
@@ -25,15 +31,37 @@ struct State<'a> {
     size: winit::dpi::PhysicalSize<u32>,
     window: &'a Window,
     render_pipeline: wgpu::RenderPipeline,
+    temporal_pipeline: wgpu::RenderPipeline,
+    upsample_pipeline: wgpu::RenderPipeline,
     depth_texture_view: TextureView,
     camera: Camera,
     camera_controller: CameraController,
     camera_uniform: CameraUniform,
     camera_buffer: wgpu::Buffer,
-    screen_size_buffer: wgpu::Buffer,
-    light_pos_buffer: wgpu::Buffer,
+    render_scale: f32,
+    screen_buffer: wgpu::Buffer,
+    cloud_target_bind_group_layout: wgpu::BindGroupLayout,
+    cloud_target: cloud_target::CloudTarget,
+    temporal_uniform: cloud_target::TemporalUniform,
+    temporal_buffer: wgpu::Buffer,
+    lights: Vec<light::Light>,
+    light_buffer: wgpu::Buffer,
+    scattering_uniform: cloud::ScatteringUniform,
+    scattering_buffer: wgpu::Buffer,
+    ambient_uniform: cloud::AmbientUniform,
+    ambient_buffer: wgpu::Buffer,
+    volume_uniform: cloud::VolumeUniform,
+    volume_buffer: wgpu::Buffer,
+    cloud_volumes: Vec<models::CloudVolume>,
+    cloud_volume_buffer: wgpu::Buffer,
+    bvh: bvh::Bvh,
+    bvh_buffer: wgpu::Buffer,
+    /// Whether the wind blows the cloud volumes across the scene.
+    wind: bool,
     raymarch_uniform_bind_group: wgpu::BindGroup,
     raymarch_texture_bind_group: wgpu::BindGroup,
+    density_grid: density_grid::DensityGrid,
+    atmosphere: atmosphere::Atmosphere,
     time: std::time::Instant,
 }
 
@@ -62,7 +90,8 @@ impl<'a> State<'a> {
 
         let (device, queue) = adapter
             .request_device(&wgpu::DeviceDescriptor {
-                required_features: wgpu::Features::empty(),
+                // Lets the density textures be filtered at full precision where it's supported.
+                required_features: adapter.features() & wgpu::Features::FLOAT32_FILTERABLE,
                 // WebGL doesn't support all of wgpu's features, so if
                 // we're building for the web, we'll have to disable some.
                 required_limits: wgpu::Limits::default(),
@@ -89,7 +118,13 @@ impl<'a> State<'a> {
 
         let raymarch_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader"),
-            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/raymarch.wgsl").into()),
+            source: wgpu::ShaderSource::Wgsl(
+                concat!(
+                    include_str!("shaders/atmosphere_common.wgsl"),
+                    include_str!("shaders/raymarch.wgsl")
+                )
+                .into(),
+            ),
         });
 
         let depth_texture_view =
@@ -114,23 +149,181 @@ impl<'a> State<'a> {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let aabb = models::AABB::new([-0.5, -0.5, -0.5], [0.5, 0.5, 0.5]);
+        // Every volume reads the first weather layer, as that's the only one a painted weather
+        // map has.
+        let cloud_volumes: Vec<_> = {
+            let cube = models::CloudVolume::new(
+                Vector3::new(0.0, 0.0, 0.0),
+                Quaternion::one(),
+                Vector3::new(1.0, 1.0, 1.0),
+                0,
+            );
 
-        let aabb_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("AABB Buffer"),
-            contents: bytemuck::cast_slice(&[aabb]),
+            // A wide, flat bank of cumulus off to the side.
+            let bank = models::CloudVolume::new(
+                Vector3::new(1.8, -0.3, -2.0),
+                Quaternion::from_angle_y(Deg(30.0)),
+                Vector3::new(2.0, 0.6, 1.2),
+                0,
+            )
+            .map(|mut bank| {
+                bank.type_override = 0.5;
+                bank
+            });
+
+            // A dense, towering cumulonimbus behind the cube, darkened by the rain in it.
+            let cumulonimbus = models::CloudVolume::new(
+                Vector3::new(-1.6, 0.3, -2.0),
+                Quaternion::from_angle_y(Deg(-20.0)),
+                Vector3::new(1.0, 1.6, 1.0),
+                0,
+            )
+            .map(|mut cumulonimbus| {
+                cumulonimbus.type_override = 1.0;
+                cumulonimbus.density_scale = 1.5;
+                cumulonimbus.absorption_scale = 4.0;
+                cumulonimbus
+            });
+
+            [
+                ("cube", cube),
+                ("bank", bank),
+                ("cumulonimbus", cumulonimbus),
+            ]
+            .into_iter()
+            .filter_map(|(name, volume)| {
+                if volume.is_none() {
+                    log::warn!("Skipping the {name} cloud volume, which has a degenerate size");
+                }
+                volume
+            })
+            .collect()
+        };
+
+        // A storage buffer can't be empty, so without volumes it holds a placeholder, which the
+        // empty BVH never points to.
+        let placeholder_volume = [models::CloudVolume::default()];
+        let cloud_volume_contents = if cloud_volumes.is_empty() {
+            &placeholder_volume[..]
+        } else {
+            &cloud_volumes[..]
+        };
+        let cloud_volume_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cloud Volume Buffer"),
+            contents: bytemuck::cast_slice(cloud_volume_contents),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bvh = bvh::Bvh::build(&cloud_volumes);
+
+        let bvh_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("BVH Buffer"),
+            contents: bytemuck::cast_slice(&bvh.nodes),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Raymarch the clouds at half resolution, then upsample them to the screen.
+        let render_scale = 0.5;
+        let screen_uniform =
+            cloud_target::ScreenUniform::new(config.width, config.height, render_scale);
+
+        let screen_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Screen Buffer"),
+            contents: bytemuck::cast_slice(&[screen_uniform]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let screen_size_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Screen Size Buffer"),
-            contents: bytemuck::cast_slice(&[config.width, config.height]),
+        let cloud_target_bind_group_layout =
+            cloud_target::CloudTarget::create_bind_group_layout(&device);
+        let cloud_target = cloud_target::CloudTarget::new(
+            &device,
+            &cloud_target_bind_group_layout,
+            &screen_uniform,
+        );
+
+        let lights = vec![
+            // The first light orbits around the volume, see `State::update`.
+            light::Light::point([0.0, 0.0, 0.0], [1.0, 1.0, 1.0], 5.0),
+            light::Light::directional([-0.4, -0.8, -0.4], [1.0, 0.95, 0.9], 0.6),
+            // Warm glow from below, like city lights under a cloud deck.
+            light::Light::spot(
+                [0.0, -1.0, 0.0],
+                [0.0, 1.0, 0.0],
+                [1.0, 0.6, 0.3],
+                2.0,
+                cgmath::Deg(20.0),
+                cgmath::Deg(40.0),
+            ),
+        ];
+
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Buffer"),
+            contents: bytemuck::cast_slice(&lights),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let cloud_uniform = cloud::CloudUniform::new();
+
+        let cloud_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cloud Buffer"),
+            contents: bytemuck::cast_slice(&[cloud_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let cloud_type_uniform = cloud::CloudTypeUniform::new();
+
+        let cloud_type_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cloud Type Buffer"),
+            contents: bytemuck::cast_slice(&[cloud_type_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let scattering_uniform = cloud::ScatteringUniform::new();
+
+        let scattering_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Scattering Buffer"),
+            contents: bytemuck::cast_slice(&[scattering_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Water droplets barely absorb any light, which is what makes clouds white.
+        let medium_uniform = cloud::MediumUniform::from_albedo([3.2, 3.2, 3.2], [0.99, 0.99, 0.99]);
+
+        let medium_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Medium Buffer"),
+            contents: bytemuck::cast_slice(&[medium_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let ambient_uniform = cloud::AmbientUniform::new();
+
+        let ambient_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Ambient Buffer"),
+            contents: bytemuck::cast_slice(&[ambient_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let temporal_uniform = cloud_target::TemporalUniform::new();
+
+        let temporal_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Temporal Buffer"),
+            contents: bytemuck::cast_slice(&[temporal_uniform]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let light_pos_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Light Pos Buffer"),
-            contents: bytemuck::cast_slice(&[0.0, 0.0, 0.0]),
+        let march_uniform = cloud::MarchUniform::new();
+
+        let march_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("March Buffer"),
+            contents: bytemuck::cast_slice(&[march_uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let volume_uniform = cloud::VolumeUniform::new();
+
+        let volume_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Volume Buffer"),
+            contents: bytemuck::cast_slice(&[volume_uniform]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
@@ -151,7 +344,7 @@ impl<'a> State<'a> {
                         binding: 1,
                         visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
                             has_dynamic_offset: false,
                             min_binding_size: None,
                         },
@@ -170,6 +363,56 @@ impl<'a> State<'a> {
                     wgpu::BindGroupLayoutEntry {
                         binding: 3,
                         visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Buffer {
                             ty: wgpu::BufferBindingType::Uniform,
                             has_dynamic_offset: false,
@@ -177,6 +420,46 @@ impl<'a> State<'a> {
                         },
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 9,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 10,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 11,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 12,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("raymarch_uniform_bind_group_layout"),
             });
@@ -190,21 +473,60 @@ impl<'a> State<'a> {
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: aabb_buffer.as_entire_binding(),
+                    resource: cloud_volume_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: screen_size_buffer.as_entire_binding(),
+                    resource: screen_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: light_pos_buffer.as_entire_binding(),
+                    resource: light_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: cloud_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: cloud_type_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: scattering_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: medium_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: ambient_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: temporal_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: march_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: volume_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: bvh_buffer.as_entire_binding(),
                 },
             ],
             label: Some("raymarch_uniform_bind_group"),
         });
 
-        let cloud_noise_texture3d = texture::create_noise_texture_3d(
+        let density_precision = texture::TexturePrecision::filterable(device.features());
+        log::info!("Density texture precision: {:?}", density_precision);
+
+        let cloud_noise_texture3d = texture::create_perlin_worley_texture_3d(
             &device,
             &queue,
             wgpu::Extent3d {
@@ -212,14 +534,126 @@ impl<'a> State<'a> {
                 height: 64,
                 depth_or_array_layers: 64,
             },
-            Some("Noise Texture 3D"),
-            0,
-            0.08,
+            density_precision,
+            Some("Perlin-Worley Noise Texture 3D"),
+            texture::FbmSettings {
+                seed: 0,
+                frequency: 0.06,
+                octaves: 4,
+                lacunarity: 2.0,
+                gain: 0.5,
+            },
+        );
+
+        let cloud_detail_texture3d = texture::create_worley_texture_3d(
+            &device,
+            &queue,
+            wgpu::Extent3d {
+                width: 32,
+                height: 32,
+                depth_or_array_layers: 32,
+            },
+            density_precision,
+            Some("Worley Detail Noise Texture 3D"),
+            texture::FbmSettings {
+                seed: 1,
+                frequency: 0.125,
+                octaves: 3,
+                lacunarity: 2.0,
+                gain: 0.5,
+            },
         );
 
-        let blue_noise_texture =
-            texture::load_texture_2d_gray(&device, &queue, &Path::new("assets/blue_noise.png"))
-                .unwrap();
+        // Artists can paint their own cloud layout into assets/weather_map.png, which has to tile
+        // to be repeated across the layer. Without it, a procedural weather map is generated
+        // instead. A file that can't be loaded falls back to the procedural map too.
+        let loaded_weather_map =
+            asset_path("WEATHER_MAP_PATH", "weather_map.png").and_then(|path| {
+                texture::load_texture_2d_rgba(&device, &queue, &path)
+                    .inspect_err(|error| {
+                        log::warn!(
+                            "Failed to load {}, using a procedural weather map: {error}",
+                            path.display()
+                        )
+                    })
+                    .ok()
+            });
+        // One map for every weather layer the cloud volumes read.
+        let weather_layer_count = cloud_volumes
+            .iter()
+            .map(|volume| volume.weather_layer + 1)
+            .max()
+            .unwrap_or(1);
+        // The loaded map is a single image, however many layers the texture got.
+        let weather_map_layers = if loaded_weather_map.is_some() {
+            1
+        } else {
+            weather_layer_count
+        };
+        let weather_map_texture = loaded_weather_map.unwrap_or_else(|| {
+            texture::create_weather_map_texture(
+                &device,
+                &queue,
+                256,
+                256,
+                weather_layer_count,
+                Some("Procedural Weather Map Texture"),
+                texture::FbmSettings {
+                    seed: 2,
+                    frequency: 1.0 / 64.0,
+                    octaves: 4,
+                    lacunarity: 2.0,
+                    gain: 0.5,
+                },
+            )
+        });
+        // The shader clamps missing layers to the last one, which is likely not what was meant.
+        for (index, volume) in cloud_volumes.iter().enumerate() {
+            if volume.weather_layer >= weather_map_layers {
+                log::warn!(
+                    "Cloud volume {index} reads weather layer {}, but the weather map only has \
+                     {weather_map_layers}",
+                    volume.weather_layer
+                );
+            }
+        }
+
+        // A different slice of the blue noise is used every frame.
+        let blue_noise_settings = texture::BlueNoiseSettings {
+            size: wgpu::Extent3d {
+                width: 32,
+                height: 32,
+                depth_or_array_layers: 16,
+            },
+            seed: 0,
+        };
+        let blue_noise_cache_path = std::env::temp_dir().join(format!(
+            "volumetric-cloud-blue-noise-{}x{}x{}-{}.bin",
+            blue_noise_settings.size.width,
+            blue_noise_settings.size.height,
+            blue_noise_settings.size.depth_or_array_layers,
+            blue_noise_settings.seed,
+        ));
+        // A precomputed mask in assets/blue_noise.png is used instead of the generated noise.
+        let loaded_blue_noise = asset_path("BLUE_NOISE_PATH", "blue_noise.png").and_then(|path| {
+            texture::load_blue_noise_texture(&device, &queue, &path)
+                .inspect_err(|error| {
+                    log::warn!(
+                        "Failed to load {}, generating blue noise instead: {error}",
+                        path.display()
+                    )
+                })
+                .ok()
+        });
+        let blue_noise_texture = loaded_blue_noise.unwrap_or_else(|| {
+            texture::create_blue_noise_texture(
+                &device,
+                &queue,
+                blue_noise_settings,
+                Some(&blue_noise_cache_path),
+                Some("Blue Noise Texture 3D"),
+            )
+        });
 
         let raymarch_texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -230,14 +664,14 @@ impl<'a> State<'a> {
                         ty: wgpu::BindingType::Texture {
                             multisampled: false,
                             view_dimension: wgpu::TextureViewDimension::D3,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         },
                         count: None,
                     },
                     wgpu::BindGroupLayoutEntry {
                         binding: 1,
                         visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None,
                     },
                     wgpu::BindGroupLayoutEntry {
@@ -245,15 +679,41 @@ impl<'a> State<'a> {
                         visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Texture {
                             multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
+                            view_dimension: wgpu::TextureViewDimension::D3,
                             sample_type: wgpu::TextureSampleType::Float { filterable: false },
                         },
                         count: None,
                     },
                     wgpu::BindGroupLayoutEntry {
-                        binding: 3,
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D3,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
                         visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None,
                     },
                 ],
@@ -271,14 +731,15 @@ impl<'a> State<'a> {
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
+                    // The base shape noise is tiled in world space, so it has to repeat.
                     resource: wgpu::BindingResource::Sampler(&device.create_sampler(
                         &wgpu::SamplerDescriptor {
-                            address_mode_u: wgpu::AddressMode::ClampToEdge,
-                            address_mode_v: wgpu::AddressMode::ClampToEdge,
-                            address_mode_w: wgpu::AddressMode::ClampToEdge,
-                            mag_filter: wgpu::FilterMode::Nearest,
-                            min_filter: wgpu::FilterMode::Nearest,
-                            mipmap_filter: wgpu::FilterMode::Nearest,
+                            address_mode_u: wgpu::AddressMode::Repeat,
+                            address_mode_v: wgpu::AddressMode::Repeat,
+                            address_mode_w: wgpu::AddressMode::Repeat,
+                            mag_filter: wgpu::FilterMode::Linear,
+                            min_filter: wgpu::FilterMode::Linear,
+                            mipmap_filter: wgpu::FilterMode::Linear,
                             ..Default::default()
                         },
                     )),
@@ -290,14 +751,45 @@ impl<'a> State<'a> {
                     ),
                 },
                 wgpu::BindGroupEntry {
-                    binding: 3,
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(
+                        &cloud_detail_texture3d.create_view(&Default::default()),
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    // The detail noise is tiled across the volume, so it has to repeat.
                     resource: wgpu::BindingResource::Sampler(&device.create_sampler(
                         &wgpu::SamplerDescriptor {
-                            address_mode_u: wgpu::AddressMode::ClampToEdge,
-                            address_mode_v: wgpu::AddressMode::ClampToEdge,
-                            address_mode_w: wgpu::AddressMode::ClampToEdge,
-                            mag_filter: wgpu::FilterMode::Nearest,
-                            min_filter: wgpu::FilterMode::Nearest,
+                            address_mode_u: wgpu::AddressMode::Repeat,
+                            address_mode_v: wgpu::AddressMode::Repeat,
+                            address_mode_w: wgpu::AddressMode::Repeat,
+                            mag_filter: wgpu::FilterMode::Linear,
+                            min_filter: wgpu::FilterMode::Linear,
+                            mipmap_filter: wgpu::FilterMode::Linear,
+                            ..Default::default()
+                        },
+                    )),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&weather_map_texture.create_view(
+                        &wgpu::TextureViewDescriptor {
+                            dimension: Some(wgpu::TextureViewDimension::D2Array),
+                            ..Default::default()
+                        },
+                    )),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    // The weather map is tiled across the infinite layer.
+                    resource: wgpu::BindingResource::Sampler(&device.create_sampler(
+                        &wgpu::SamplerDescriptor {
+                            address_mode_u: wgpu::AddressMode::Repeat,
+                            address_mode_v: wgpu::AddressMode::Repeat,
+                            address_mode_w: wgpu::AddressMode::Repeat,
+                            mag_filter: wgpu::FilterMode::Linear,
+                            min_filter: wgpu::FilterMode::Linear,
                             mipmap_filter: wgpu::FilterMode::Nearest,
                             ..Default::default()
                         },
@@ -307,6 +799,23 @@ impl<'a> State<'a> {
             label: Some("raymarch_texture_bind_group"),
         });
 
+        let atmosphere =
+            atmosphere::Atmosphere::new(&device, &queue, atmosphere::AtmosphereUniform::new());
+
+        let density_grid = density_grid::DensityGrid::new(
+            &device,
+            &raymarch_shader,
+            &raymarch_uniform_bind_group_layout,
+            &raymarch_texture_bind_group_layout,
+            cloud_volumes.len() as u32,
+        );
+        density_grid.update(
+            &device,
+            &queue,
+            &raymarch_uniform_bind_group,
+            &raymarch_texture_bind_group,
+        );
+
         let camera_controller = CameraController::new(0.02, 0.005);
 
         let render_pipeline_layout =
@@ -315,6 +824,8 @@ impl<'a> State<'a> {
                 bind_group_layouts: &[
                     &raymarch_uniform_bind_group_layout,
                     &raymarch_texture_bind_group_layout,
+                    &atmosphere.bind_group_layout,
+                    &density_grid.bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             });
@@ -331,11 +842,18 @@ impl<'a> State<'a> {
             fragment: Some(wgpu::FragmentState {
                 module: &raymarch_shader,
                 entry_point: Some("fs_main"),
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
+                targets: &[
+                    Some(wgpu::ColorTargetState {
+                        format: cloud_target::COLOR_FORMAT,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }),
+                    Some(wgpu::ColorTargetState {
+                        format: cloud_target::DEPTH_FORMAT,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }),
+                ],
                 compilation_options: wgpu::PipelineCompilationOptions::default(),
             }),
             primitive: wgpu::PrimitiveState {
@@ -350,6 +868,85 @@ impl<'a> State<'a> {
                 // Requires Features::CONSERVATIVE_RASTERIZATION
                 conservative: false,
             },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+            cache: None,
+        });
+
+        // Shared by the temporal and the upsample pipelines.
+        let cloud_target_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Cloud Target Pipeline Layout"),
+                bind_group_layouts: &[
+                    &raymarch_uniform_bind_group_layout,
+                    &raymarch_texture_bind_group_layout,
+                    &atmosphere.bind_group_layout,
+                    &cloud_target_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+        let temporal_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Temporal Pipeline"),
+            layout: Some(&cloud_target_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &raymarch_shader,
+                entry_point: Some("vs_main"),
+                buffers: &[],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &raymarch_shader,
+                entry_point: Some("fs_temporal"),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: cloud_target::COLOR_FORMAT,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        let upsample_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Upsample Pipeline"),
+            layout: Some(&cloud_target_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &raymarch_shader,
+                entry_point: Some("vs_main"),
+                buffers: &[],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &raymarch_shader,
+                entry_point: Some("fs_upsample"),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: config.format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
             depth_stencil: Some(wgpu::DepthStencilState {
                 format: texture::DEPTH_FORMAT,
                 depth_write_enabled: true,
@@ -357,11 +954,7 @@ impl<'a> State<'a> {
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
+            multisample: wgpu::MultisampleState::default(),
             multiview: None,
             cache: None,
         });
@@ -376,21 +969,42 @@ impl<'a> State<'a> {
             size,
             window,
             render_pipeline,
+            temporal_pipeline,
+            upsample_pipeline,
             depth_texture_view,
             camera,
             camera_controller,
             camera_uniform,
             camera_buffer,
-            screen_size_buffer,
-            light_pos_buffer,
+            render_scale,
+            screen_buffer,
+            cloud_target_bind_group_layout,
+            cloud_target,
+            temporal_uniform,
+            temporal_buffer,
+            lights,
+            light_buffer,
+            scattering_uniform,
+            scattering_buffer,
+            ambient_uniform,
+            ambient_buffer,
+            volume_uniform,
+            volume_buffer,
+            cloud_volumes,
+            cloud_volume_buffer,
+            bvh,
+            bvh_buffer,
+            wind: false,
             raymarch_uniform_bind_group,
             raymarch_texture_bind_group,
+            density_grid,
+            atmosphere,
             time,
         }
     }
 
     pub fn window(&self) -> &Window {
-        &self.window
+        self.window
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -402,16 +1016,167 @@ impl<'a> State<'a> {
             self.depth_texture_view =
                 texture::create_depth_texture_view(&self.device, &self.config, "depth_texture");
             self.camera.aspect = new_size.width as f32 / new_size.height as f32;
-            self.queue.write_buffer(
-                &self.screen_size_buffer,
-                0,
-                bytemuck::cast_slice(&[new_size.width, new_size.height]),
-            );
+            self.resize_cloud_target();
         }
     }
 
+    // Recreates the cloud target after the screen size or the render scale changed.
+    fn resize_cloud_target(&mut self) {
+        let screen_uniform = cloud_target::ScreenUniform::new(
+            self.config.width,
+            self.config.height,
+            self.render_scale,
+        );
+        self.cloud_target = cloud_target::CloudTarget::new(
+            &self.device,
+            &self.cloud_target_bind_group_layout,
+            &screen_uniform,
+        );
+        self.queue.write_buffer(
+            &self.screen_buffer,
+            0,
+            bytemuck::cast_slice(&[screen_uniform]),
+        );
+        // The history of the old target can't be reprojected onto the new one.
+        self.temporal_uniform.history_valid = 0;
+    }
+
+    /// Besides moving the camera with WASD, Space and left Shift, and dragging the mouse to look
+    /// around, the keys are:
+    ///
+    /// - M: toggle multiple scattering.
+    /// - P: cycle through the phase functions.
+    /// - E: cycle through the ambient light modes.
+    /// - V: cycle through the volume modes.
+    /// - G: toggle the wind blowing the cloud volumes.
+    /// - T: toggle the temporal accumulation.
+    /// - R: cycle through the render scales of the clouds.
     fn input(&mut self, event: &WindowEvent) -> bool {
-        self.camera_controller.process_events(event)
+        match event {
+            // Toggle multiple scattering to compare it against single scattering.
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(KeyCode::KeyM),
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                self.scattering_uniform.toggle_multiple_scattering();
+                self.queue.write_buffer(
+                    &self.scattering_buffer,
+                    0,
+                    bytemuck::cast_slice(&[self.scattering_uniform]),
+                );
+                true
+            }
+            // Cycle through the phase functions.
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(KeyCode::KeyP),
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                let phase_function = self.scattering_uniform.cycle_phase_function();
+                log::info!("Phase function: {:?}", phase_function);
+                self.queue.write_buffer(
+                    &self.scattering_buffer,
+                    0,
+                    bytemuck::cast_slice(&[self.scattering_uniform]),
+                );
+                true
+            }
+            // Cycle through the ambient light modes.
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(KeyCode::KeyE),
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                let ambient_mode = self.ambient_uniform.cycle_mode();
+                log::info!("Ambient mode: {:?}", ambient_mode);
+                self.queue.write_buffer(
+                    &self.ambient_buffer,
+                    0,
+                    bytemuck::cast_slice(&[self.ambient_uniform]),
+                );
+                true
+            }
+            // Cycle through the box, the infinite layer and the spherical shell of clouds.
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(KeyCode::KeyV),
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                let volume_mode = self.volume_uniform.cycle_mode();
+                log::info!("Volume mode: {:?}", volume_mode);
+                self.temporal_uniform.history_valid = 0;
+                true
+            }
+            // Toggle the wind blowing the cloud volumes.
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(KeyCode::KeyG),
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                self.wind = !self.wind;
+                log::info!("Wind: {}", self.wind);
+                true
+            }
+            // Toggle the temporal accumulation of the clouds.
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(KeyCode::KeyT),
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                self.temporal_uniform.enabled = (self.temporal_uniform.enabled == 0) as u32;
+                true
+            }
+            // Cycle through the resolutions the clouds are rendered at.
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        state: ElementState::Pressed,
+                        physical_key: PhysicalKey::Code(KeyCode::KeyR),
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                let scales = cloud_target::RENDER_SCALES;
+                let current = scales.iter().position(|&scale| scale == self.render_scale);
+                self.render_scale = scales[current.map_or(0, |i| (i + 1) % scales.len())];
+                log::info!("Cloud render scale: {}", self.render_scale);
+                self.resize_cloud_target();
+                true
+            }
+            _ => self.camera_controller.process_events(event),
+        }
     }
 
     fn update(&mut self) {
@@ -422,13 +1187,78 @@ impl<'a> State<'a> {
             0,
             bytemuck::cast_slice(&[self.camera_uniform]),
         );
-        const RADIUS: f32 = 2.0;
-        let time = self.time.elapsed().as_secs_f32();
+        self.volume_uniform.update_camera(self.camera.eye);
+        self.queue.write_buffer(
+            &self.volume_buffer,
+            0,
+            bytemuck::cast_slice(&[self.volume_uniform]),
+        );
+        if let Some(orbiting_light) = self.lights.first_mut() {
+            const RADIUS: f32 = 2.0;
+            let time = self.time.elapsed().as_secs_f32();
+            orbiting_light.position = [RADIUS * Rad(time).cos(), 1.0, RADIUS * Rad(time).sin()];
+        }
+        self.queue
+            .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&self.lights));
+
+        self.temporal_uniform.cloud_motion = [0.0; 3];
+        if self.wind {
+            self.blow_cloud_volumes();
+        }
+
+        // The sky is lit by the first directional light.
+        if let Some(sun) = self
+            .lights
+            .iter()
+            .find(|light| light.kind() == light::LightKind::Directional)
+        {
+            let sun_direction = -Vector3::from(sun.direction).normalize();
+            self.atmosphere.uniform.sun_direction = sun_direction.into();
+        }
+        // One world unit is treated as a kilometer, and the ground is the surface of the planet
+        // under the shell.
+        self.atmosphere.uniform.camera_altitude = self.volume_uniform.camera_altitude.max(0.0);
+        self.atmosphere.write_uniform(&self.queue);
+    }
+
+    /// Moves the cloud volumes with the wind along x. Volumes blown too far away come back on
+    /// the other side of the scene.
+    fn blow_cloud_volumes(&mut self) {
+        const WIND_SPEED: f32 = 0.005;
+        const WRAP_DISTANCE: f32 = 6.0;
+
+        let offset = Vector3::unit_x() * WIND_SPEED;
+        // Only the boxes move, the layer and the shell don't use the volumes. A volume that
+        // wraps around jumps farther, and its history is rejected by the clamp instead.
+        if self.volume_uniform.mode == cloud::VolumeMode::Box as u32 {
+            self.temporal_uniform.cloud_motion = offset.into();
+        }
+
+        let mut wrapped = false;
+        for volume in &mut self.cloud_volumes {
+            volume.translate(offset);
+            if volume.position().x > WRAP_DISTANCE {
+                volume.translate(Vector3::unit_x() * -2.0 * WRAP_DISTANCE);
+                wrapped = true;
+            }
+        }
+
+        // Refitting is enough while the volumes move together, but a volume jumping to the
+        // other side would leave huge nodes behind, so the hierarchy is built again then. The
+        // density grids are in the local space of the volumes, so they stay valid either way.
+        if wrapped {
+            self.bvh = bvh::Bvh::build(&self.cloud_volumes);
+        } else {
+            self.bvh.refit(&self.cloud_volumes);
+        }
+
         self.queue.write_buffer(
-            &self.light_pos_buffer,
+            &self.cloud_volume_buffer,
             0,
-            bytemuck::cast_slice(&[RADIUS * Rad(time).cos(), 1.0, RADIUS * Rad(time).sin()]),
+            bytemuck::cast_slice(&self.cloud_volumes),
         );
+        self.queue
+            .write_buffer(&self.bvh_buffer, 0, bytemuck::cast_slice(&self.bvh.nodes));
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -442,6 +1272,77 @@ impl<'a> State<'a> {
                 label: Some("Render Encoder"),
             });
 
+        self.atmosphere.render_sky_view_lut(&mut encoder);
+
+        self.queue.write_buffer(
+            &self.temporal_buffer,
+            0,
+            bytemuck::cast_slice(&[self.temporal_uniform]),
+        );
+        // This frame is accumulated into one history texture, reading the other one.
+        let history_write = (self.temporal_uniform.frame % 2) as usize;
+        let history_read = 1 - history_write;
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Cloud Render Pass"),
+                color_attachments: &[
+                    Some(wgpu::RenderPassColorAttachment {
+                        view: &self.cloud_target.color_view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    }),
+                    Some(wgpu::RenderPassColorAttachment {
+                        view: &self.cloud_target.depth_view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    }),
+                ],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            // Draw full screen quad.
+            render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_bind_group(0, &self.raymarch_uniform_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.raymarch_texture_bind_group, &[]);
+            render_pass.set_bind_group(2, &self.atmosphere.bind_group, &[]);
+            render_pass.set_bind_group(3, &self.density_grid.bind_group, &[]);
+            // No vertex buffer. The vertices are hardcoded in the vertex shader.
+            render_pass.draw(0..6, 0..1);
+        }
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Temporal Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.cloud_target.history_views[history_write],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            render_pass.set_pipeline(&self.temporal_pipeline);
+            render_pass.set_bind_group(0, &self.raymarch_uniform_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.raymarch_texture_bind_group, &[]);
+            render_pass.set_bind_group(2, &self.atmosphere.bind_group, &[]);
+            render_pass.set_bind_group(3, &self.cloud_target.bind_groups[history_read], &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
@@ -449,12 +1350,8 @@ impl<'a> State<'a> {
                     view: &view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
+                        // Every pixel is covered by the sky, so the clear color is never seen.
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
@@ -470,11 +1367,12 @@ impl<'a> State<'a> {
                 occlusion_query_set: None,
             });
 
-            // Draw full screen quad.
-            render_pass.set_pipeline(&self.render_pipeline);
+            // Upsample the clouds and composite them over the sky.
+            render_pass.set_pipeline(&self.upsample_pipeline);
             render_pass.set_bind_group(0, &self.raymarch_uniform_bind_group, &[]);
             render_pass.set_bind_group(1, &self.raymarch_texture_bind_group, &[]);
-            // No vertex buffer. The vertices are hardcoded in the vertex shader.
+            render_pass.set_bind_group(2, &self.atmosphere.bind_group, &[]);
+            render_pass.set_bind_group(3, &self.cloud_target.bind_groups[history_write], &[]);
             render_pass.draw(0..6, 0..1);
         }
 
@@ -482,10 +1380,24 @@ impl<'a> State<'a> {
         self.queue.submit(std::iter::once(encoder.finish()));
         output.present();
 
+        self.temporal_uniform.frame = self.temporal_uniform.frame.wrapping_add(1);
+        self.temporal_uniform.history_valid = 1;
+
         Ok(())
     }
 }
 
+/// Path of an optional asset. The environment variable `var` overrides it, and otherwise it is
+/// `file` in the assets directory next to the executable, if there is one.
+fn asset_path(var: &str, file: &str) -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os(var) {
+        return Some(PathBuf::from(path));
+    }
+    let exe = std::env::current_exe().ok()?;
+    let path = exe.parent()?.join("assets").join(file);
+    path.exists().then_some(path)
+}
+
 pub async fn run() {
     env_logger::init();
     let event_loop = EventLoop::new().unwrap();
@@ -498,49 +1410,45 @@ pub async fn run() {
             Event::WindowEvent {
                 ref event,
                 window_id,
-            } if window_id == state.window().id() => {
-                if !state.input(event) {
-                    match event {
-                        WindowEvent::CloseRequested
-                        | WindowEvent::KeyboardInput {
-                            event:
-                                KeyEvent {
-                                    state: ElementState::Pressed,
-                                    physical_key: PhysicalKey::Code(KeyCode::Escape),
-                                    ..
-                                },
-                            ..
-                        } => control_flow.exit(),
-                        WindowEvent::Resized(physical_size) => {
-                            state.resize(*physical_size);
-                        }
-                        WindowEvent::RedrawRequested => {
-                            // This tells winit that we want another frame after this one
-                            state.window().request_redraw();
-
-                            state.update();
-                            match state.render() {
-                                Ok(_) => {}
-                                // Reconfigure the surface if it's lost or outdated
-                                Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
-                                    state.resize(state.size)
-                                }
-                                // The system is out of memory, we should probably quit
-                                Err(
-                                    wgpu::SurfaceError::OutOfMemory | wgpu::SurfaceError::Other,
-                                ) => {
-                                    log::error!("OutOfMemory");
-                                    control_flow.exit();
-                                }
-
-                                // This happens when the a frame takes too long to present
-                                Err(wgpu::SurfaceError::Timeout) => {
-                                    log::warn!("Surface timeout")
-                                }
+            } if window_id == state.window().id() && !state.input(event) => {
+                match event {
+                    WindowEvent::CloseRequested
+                    | WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                state: ElementState::Pressed,
+                                physical_key: PhysicalKey::Code(KeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    } => control_flow.exit(),
+                    WindowEvent::Resized(physical_size) => {
+                        state.resize(*physical_size);
+                    }
+                    WindowEvent::RedrawRequested => {
+                        // This tells winit that we want another frame after this one
+                        state.window().request_redraw();
+
+                        state.update();
+                        match state.render() {
+                            Ok(_) => {}
+                            // Reconfigure the surface if it's lost or outdated
+                            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                                state.resize(state.size)
+                            }
+                            // The system is out of memory, we should probably quit
+                            Err(wgpu::SurfaceError::OutOfMemory | wgpu::SurfaceError::Other) => {
+                                log::error!("OutOfMemory");
+                                control_flow.exit();
+                            }
+
+                            // This happens when the a frame takes too long to present
+                            Err(wgpu::SurfaceError::Timeout) => {
+                                log::warn!("Surface timeout")
                             }
                         }
-                        _ => {}
                     }
+                    _ => {}
                 }
             }
             _ => {}
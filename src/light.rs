@@ -0,0 +1,80 @@
+use cgmath::{Angle, Deg};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LightKind {
+    /// Emits light in every direction from `position`.
+    Point = 0,
+    /// Emits parallel light along `direction`, like the sun.
+    Directional = 1,
+    /// Emits light from `position` in a cone around `direction`.
+    Spot = 2,
+}
+
+/// A light as stored in the light storage buffer. Point and spot lights fall off with the
+/// inverse square of the distance, directional lights don't fall off at all.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct Light {
+    pub position: [f32; 3],
+    /// One of [`LightKind`] as `u32`.
+    kind: u32,
+    /// The direction the light travels in. Not used by point lights.
+    pub direction: [f32; 3],
+    pub intensity: f32,
+    pub color: [f32; 3],
+    /// Cosine of the angle inside which a spot light has full intensity.
+    cos_inner_angle: f32,
+    /// Cosine of the angle outside which a spot light has no intensity.
+    cos_outer_angle: f32,
+    _padding: [f32; 3],
+}
+
+impl Light {
+    pub fn point(position: [f32; 3], color: [f32; 3], intensity: f32) -> Self {
+        Self {
+            position,
+            kind: LightKind::Point as u32,
+            intensity,
+            color,
+            ..Default::default()
+        }
+    }
+
+    pub fn directional(direction: [f32; 3], color: [f32; 3], intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Directional as u32,
+            direction,
+            intensity,
+            color,
+            ..Default::default()
+        }
+    }
+
+    pub fn spot(
+        position: [f32; 3],
+        direction: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+        inner_angle: Deg<f32>,
+        outer_angle: Deg<f32>,
+    ) -> Self {
+        Self {
+            position,
+            kind: LightKind::Spot as u32,
+            direction,
+            intensity,
+            color,
+            cos_inner_angle: inner_angle.cos(),
+            cos_outer_angle: outer_angle.cos(),
+            ..Default::default()
+        }
+    }
+
+    pub fn kind(&self) -> LightKind {
+        match self.kind {
+            0 => LightKind::Point,
+            1 => LightKind::Directional,
+            _ => LightKind::Spot,
+        }
+    }
+}
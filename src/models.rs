@@ -1,18 +1,93 @@
+use cgmath::{Matrix4, Point3, Quaternion, SquareMatrix, Transform, Vector3};
+
+/// A box of clouds, stored in the cloud volume storage buffer. In its local space it is the
+/// cube [-0.5, 0.5]³, which is scaled, rotated and then translated into the world.
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
-pub struct AABB {
-    pub min: [f32; 3],
-    _padding1: f32,
-    pub max: [f32; 3],
-    _padding2: f32,
+pub struct CloudVolume {
+    world_to_local: [[f32; 4]; 4],
+    local_to_world: [[f32; 4]; 4],
+    /// Extent of the box along its local axes, in world space.
+    size: [f32; 3],
+    /// Layer of the weather map the box reads its clouds from.
+    pub weather_layer: u32,
+    /// Multiplier on the density of the clouds in the box.
+    pub density_scale: f32,
+    /// Multiplier on how much the detail noise erodes the clouds in the box.
+    pub erosion_scale: f32,
+    /// Cloud type in [0, 1] for the whole box, or negative to use the global one.
+    pub type_override: f32,
+    /// Multiplier on the scattering coefficient of the medium in the box.
+    pub scattering_scale: f32,
+    /// Multiplier on the absorption coefficient of the medium in the box.
+    pub absorption_scale: f32,
+    _padding: [f32; 3],
 }
 
-impl AABB {
-    pub fn new(min: [f32; 3], max: [f32; 3]) -> Self {
-        Self {
-            min,
-            max,
+impl CloudVolume {
+    /// Returns `None` if a side of the box isn't a positive, finite and normal number, as the
+    /// box couldn't be transformed back into its local space then.
+    pub fn new(
+        translation: Vector3<f32>,
+        rotation: Quaternion<f32>,
+        size: Vector3<f32>,
+        weather_layer: u32,
+    ) -> Option<Self> {
+        if ![size.x, size.y, size.z]
+            .iter()
+            .all(|side| side.is_normal() && *side > 0.0)
+        {
+            return None;
+        }
+
+        let local_to_world = Matrix4::from_translation(translation)
+            * Matrix4::from(rotation)
+            * Matrix4::from_nonuniform_scale(size.x, size.y, size.z);
+
+        Some(Self {
+            world_to_local: local_to_world.invert()?.into(),
+            local_to_world: local_to_world.into(),
+            size: size.into(),
+            weather_layer,
+            density_scale: 1.0,
+            erosion_scale: 1.0,
+            type_override: -1.0,
+            scattering_scale: 1.0,
+            absorption_scale: 1.0,
             ..Default::default()
+        })
+    }
+
+    pub fn position(&self) -> Vector3<f32> {
+        Matrix4::from(self.local_to_world).w.truncate()
+    }
+
+    /// Moves the box by `offset` in world space.
+    pub fn translate(&mut self, offset: Vector3<f32>) {
+        // Undoing the translation first keeps the inverse without inverting again.
+        self.world_to_local =
+            (Matrix4::from(self.world_to_local) * Matrix4::from_translation(-offset)).into();
+        self.local_to_world =
+            (Matrix4::from_translation(offset) * Matrix4::from(self.local_to_world)).into();
+    }
+
+    /// Minimum and maximum corner of the world space AABB around the box.
+    pub fn bounds(&self) -> ([f32; 3], [f32; 3]) {
+        let local_to_world = Matrix4::from(self.local_to_world);
+        let mut min = [f32::INFINITY; 3];
+        let mut max = [f32::NEG_INFINITY; 3];
+        for corner in 0..8 {
+            let local = Point3::new(
+                if corner & 1 == 0 { -0.5 } else { 0.5 },
+                if corner & 2 == 0 { -0.5 } else { 0.5 },
+                if corner & 4 == 0 { -0.5 } else { 0.5 },
+            );
+            let world = local_to_world.transform_point(local);
+            for axis in 0..3 {
+                min[axis] = min[axis].min(world[axis]);
+                max[axis] = max[axis].max(world[axis]);
+            }
         }
+        (min, max)
     }
 }
@@ -0,0 +1,100 @@
+// Renders the lookup tables of the atmosphere. `atmosphere_common.wgsl` is prepended to this file.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    switch(vertex_index) {
+        case 0: {out.clip_position = vec4<f32>(-1.0, -1.0, 0.0, 1.0); break;}
+        case 1: {out.clip_position = vec4<f32>(1.0, -1.0, 0.0, 1.0); break;}
+        case 2: {out.clip_position = vec4<f32>(1.0, 1.0, 0.0, 1.0); break;}
+        case 3: {out.clip_position = vec4<f32>(1.0, 1.0, 0.0, 1.0); break;}
+        case 4: {out.clip_position = vec4<f32>(-1.0, 1.0, 0.0, 1.0); break;}
+        case 5: {out.clip_position = vec4<f32>(-1.0, -1.0, 0.0, 1.0); break;}
+        default: {out.clip_position = vec4<f32>(0.0, 0.0, 0.0, 1.0); break;}
+    }
+
+    return out;
+}
+
+@group(0) @binding(0)
+var<uniform> atmosphere: AtmosphereUniform;
+@group(0) @binding(1)
+var texture_transmittance_lut: texture_2d<f32>;
+@group(0) @binding(2)
+var sampler_transmittance_lut: sampler;
+
+const TRANSMITTANCE_STEPS: i32 = 40;
+const SKY_VIEW_STEPS: i32 = 32;
+
+// Transmittance from a point at some altitude to the top of the atmosphere, in some direction.
+@fragment
+fn fs_transmittance_lut(@builtin(position) frag_coord: vec4<f32>) -> @location(0) vec4<f32> {
+    let parameters = transmittance_lut_parameters(atmosphere, frag_coord.xy / TRANSMITTANCE_LUT_SIZE);
+    let origin = vec3<f32>(0.0, atmosphere.bottom_radius + parameters.x, 0.0);
+    let cos_zenith = parameters.y;
+    let direction = vec3<f32>(sqrt(1.0 - cos_zenith * cos_zenith), cos_zenith, 0.0);
+
+    if (ray_sphere_near(origin, direction, atmosphere.bottom_radius) > 0.0) {
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0); // the planet blocks the light
+    }
+
+    let t_max = ray_sphere_far(origin, direction, atmosphere.top_radius);
+    let step = t_max / f32(TRANSMITTANCE_STEPS);
+
+    var optical_depth = vec3<f32>(0.0);
+    for (var i = 0; i < TRANSMITTANCE_STEPS; i++) {
+        let pos = origin + direction * (f32(i) + 0.5) * step;
+        let altitude = length(pos) - atmosphere.bottom_radius;
+        optical_depth += sample_atmosphere(atmosphere, altitude).extinction * step;
+    }
+
+    return vec4<f32>(exp(-optical_depth), 1.0);
+}
+
+// Single scattered sky radiance seen from the camera, in every direction.
+@fragment
+fn fs_sky_view_lut(@builtin(position) frag_coord: vec4<f32>) -> @location(0) vec4<f32> {
+    let direction = sky_view_lut_direction(frag_coord.xy / SKY_VIEW_LUT_SIZE);
+    let origin = vec3<f32>(0.0, atmosphere.bottom_radius + max(atmosphere.camera_altitude, 0.001), 0.0);
+
+    var t_max = ray_sphere_far(origin, direction, atmosphere.top_radius);
+    let t_ground = ray_sphere_near(origin, direction, atmosphere.bottom_radius);
+    if (t_ground > 0.0) {
+        t_max = t_ground;
+    }
+    let step = max(t_max, 0.0) / f32(SKY_VIEW_STEPS);
+
+    let cos_theta = dot(direction, atmosphere.sun_direction);
+    let rayleigh_phase = rayleigh_phase(cos_theta);
+    let mie_phase = mie_phase(cos_theta, atmosphere.mie_g);
+
+    var radiance = vec3<f32>(0.0);
+    var transmittance = vec3<f32>(1.0);
+    for (var i = 0; i < SKY_VIEW_STEPS; i++) {
+        let pos = origin + direction * (f32(i) + 0.5) * step;
+        let altitude = length(pos) - atmosphere.bottom_radius;
+        let sample = sample_atmosphere(atmosphere, altitude);
+
+        let cos_sun_zenith = dot(normalize(pos), atmosphere.sun_direction);
+        let sun_transmittance = textureSampleLevel(
+            texture_transmittance_lut,
+            sampler_transmittance_lut,
+            transmittance_lut_uv(atmosphere, altitude, cos_sun_zenith),
+            0.0,
+        ).rgb;
+
+        let scattering = sample.rayleigh_scattering * rayleigh_phase + sample.mie_scattering * mie_phase;
+        let in_scattering = atmosphere.sun_illuminance * sun_transmittance * scattering;
+
+        // Integrate the in-scattering analytically over the step, assuming constant extinction.
+        let step_transmittance = exp(-sample.extinction * step);
+        radiance += transmittance * (in_scattering - in_scattering * step_transmittance) / sample.extinction;
+        transmittance *= step_transmittance;
+    }
+
+    return vec4<f32>(radiance, 1.0);
+}
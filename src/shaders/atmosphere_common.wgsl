@@ -0,0 +1,113 @@
+// Shared by every shader that renders or samples the atmosphere. Distances are in kilometers.
+
+struct AtmosphereUniform {
+    // Direction towards the sun.
+    sun_direction: vec3<f32>,
+    sun_illuminance: f32,
+    rayleigh_scattering: vec3<f32>,
+    rayleigh_scale_height: f32,
+    mie_scattering: f32,
+    mie_absorption: f32,
+    mie_scale_height: f32,
+    mie_g: f32,
+    ozone_absorption: vec3<f32>,
+    camera_altitude: f32,
+    bottom_radius: f32,
+    top_radius: f32,
+}
+
+const PI: f32 = 3.14159265;
+
+// Must match `atmosphere::TRANSMITTANCE_LUT_SIZE` and `atmosphere::SKY_VIEW_LUT_SIZE`.
+const TRANSMITTANCE_LUT_SIZE: vec2<f32> = vec2<f32>(256.0, 64.0);
+const SKY_VIEW_LUT_SIZE: vec2<f32> = vec2<f32>(192.0, 108.0);
+
+// The ozone layer is modelled as a tent centered at this altitude.
+const OZONE_CENTER_ALTITUDE: f32 = 25.0;
+const OZONE_HALF_WIDTH: f32 = 15.0;
+
+struct AtmosphereSample {
+    rayleigh_scattering: vec3<f32>,
+    mie_scattering: vec3<f32>,
+    extinction: vec3<f32>,
+}
+
+fn sample_atmosphere(atmosphere: AtmosphereUniform, altitude: f32) -> AtmosphereSample {
+    let rayleigh_density = exp(-altitude / atmosphere.rayleigh_scale_height);
+    let mie_density = exp(-altitude / atmosphere.mie_scale_height);
+    let ozone_density = max(0.0, 1.0 - abs(altitude - OZONE_CENTER_ALTITUDE) / OZONE_HALF_WIDTH);
+
+    var sample: AtmosphereSample;
+    sample.rayleigh_scattering = atmosphere.rayleigh_scattering * rayleigh_density;
+    sample.mie_scattering = vec3<f32>(atmosphere.mie_scattering * mie_density);
+    sample.extinction = sample.rayleigh_scattering
+        + vec3<f32>((atmosphere.mie_scattering + atmosphere.mie_absorption) * mie_density)
+        + atmosphere.ozone_absorption * ozone_density;
+    return sample;
+}
+
+// Distance along the ray to where it leaves the sphere centered at the planet center,
+// or a negative value if it doesn't hit the sphere at all.
+fn ray_sphere_far(origin: vec3<f32>, direction: vec3<f32>, radius: f32) -> f32 {
+    let b = dot(origin, direction);
+    let c = dot(origin, origin) - radius * radius;
+    let discriminant = b * b - c;
+    if (discriminant < 0.0) {
+        return -1.0;
+    }
+    return -b + sqrt(discriminant);
+}
+
+// Distance along the ray to where it enters the sphere centered at the planet center,
+// or a negative value if it doesn't hit the sphere in front of the origin.
+fn ray_sphere_near(origin: vec3<f32>, direction: vec3<f32>, radius: f32) -> f32 {
+    let b = dot(origin, direction);
+    let c = dot(origin, origin) - radius * radius;
+    let discriminant = b * b - c;
+    if (discriminant < 0.0) {
+        return -1.0;
+    }
+    return -b - sqrt(discriminant);
+}
+
+fn rayleigh_phase(cos_theta: f32) -> f32 {
+    return 3.0 / (16.0 * PI) * (1.0 + cos_theta * cos_theta);
+}
+
+// Cornette-Shanks, normalized over the sphere.
+fn mie_phase(cos_theta: f32, g: f32) -> f32 {
+    let g2 = g * g;
+    let numerator = (1.0 - g2) * (1.0 + cos_theta * cos_theta);
+    let denominator = (2.0 + g2) * pow(1.0 + g2 - 2.0 * g * cos_theta, 1.5);
+    return 3.0 / (8.0 * PI) * numerator / denominator;
+}
+
+// The square root spends more texels on the low altitudes, where the air is densest.
+fn transmittance_lut_uv(atmosphere: AtmosphereUniform, altitude: f32, cos_zenith: f32) -> vec2<f32> {
+    let height = atmosphere.top_radius - atmosphere.bottom_radius;
+    return vec2<f32>(cos_zenith * 0.5 + 0.5, sqrt(saturate(altitude / height)));
+}
+
+// Returns (altitude, cos_zenith), the inverse of `transmittance_lut_uv`.
+fn transmittance_lut_parameters(atmosphere: AtmosphereUniform, uv: vec2<f32>) -> vec2<f32> {
+    let height = atmosphere.top_radius - atmosphere.bottom_radius;
+    return vec2<f32>(uv.y * uv.y * height, uv.x * 2.0 - 1.0);
+}
+
+// Maps a world space direction to the sky-view LUT. The elevation mapping is non-linear
+// to spend more texels around the horizon, where the sky color changes the most.
+fn sky_view_lut_uv(direction: vec3<f32>) -> vec2<f32> {
+    let azimuth = atan2(direction.z, direction.x);
+    let elevation = asin(clamp(direction.y, -1.0, 1.0));
+    let v = 0.5 + 0.5 * sign(elevation) * sqrt(abs(elevation) / (PI * 0.5));
+    return vec2<f32>(azimuth / (2.0 * PI) + 0.5, 1.0 - v);
+}
+
+// The inverse of `sky_view_lut_uv`.
+fn sky_view_lut_direction(uv: vec2<f32>) -> vec3<f32> {
+    let azimuth = (uv.x - 0.5) * 2.0 * PI;
+    let v = (1.0 - uv.y) * 2.0 - 1.0;
+    let elevation = sign(v) * v * v * PI * 0.5;
+    return vec3<f32>(cos(elevation) * cos(azimuth), sin(elevation), cos(elevation) * sin(azimuth));
+}
+
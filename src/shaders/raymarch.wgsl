@@ -1,13 +1,190 @@
+// `atmosphere_common.wgsl` is prepended to this file.
+
 struct CameraUniform {
     view_proj_inv: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
+    view_proj: mat4x4<f32>,
     cam_pos: vec3<f32>,
 };
 
-struct AABBUniform {
+struct ScreenUniform {
+    size: vec2<u32>,
+    // Size of the offscreen target the clouds are rendered into.
+    cloud_size: vec2<u32>,
+}
+
+struct TemporalUniform {
+    history_weight: f32,
+    enabled: u32,
+    history_valid: u32,
+    frame: u32,
+    cloud_motion: vec3<f32>,
+}
+
+struct AABB {
     min: vec3<f32>,
     max: vec3<f32>,
 }
 
+// See `models::CloudVolume`. The volume is the cube [-0.5, 0.5]³ in its local space.
+struct CloudVolume {
+    world_to_local: mat4x4<f32>,
+    local_to_world: mat4x4<f32>,
+    size: vec3<f32>,
+    weather_layer: u32,
+    density_scale: f32,
+    erosion_scale: f32,
+    type_override: f32,
+    scattering_scale: f32,
+    absorption_scale: f32,
+}
+
+// See `bvh::BvhNode`.
+struct BvhNode {
+    min: vec3<f32>,
+    first_child: u32,
+    max: vec3<f32>,
+    volume: u32,
+}
+
+// Marks an interior node in `BvhNode::volume`.
+const BVH_INTERIOR: u32 = 0xffffffffu;
+// Deep enough for the balanced hierarchy over any number of volumes that fits in memory, and
+// `bvh::Bvh::build` panics on a deeper one. Must match `bvh::BVH_STACK_SIZE`.
+const BVH_STACK_SIZE: u32 = 32u;
+
+// The part of a ray inside the cloud volume `index`.
+struct VolumeInterval {
+    t_min: f32,
+    t_max: f32,
+    index: u32,
+}
+
+// More volumes than this along a single ray are dropped, starting with the farthest ones.
+const MAX_RAY_VOLUMES: u32 = 16u;
+
+// The cloud volumes along a ray, ordered by where the ray enters them.
+struct RayVolumes {
+    intervals: array<VolumeInterval, MAX_RAY_VOLUMES>,
+    count: u32,
+}
+
+// The base shape noise rarely goes below this value, so it is stretched from here to [0, 1].
+const BASE_SHAPE_MIN: f32 = 0.6;
+
+struct CloudUniform {
+    detail_scale: f32,
+    erosion_strength: f32,
+    density_multiplier: f32,
+    noise_scale: f32,
+}
+
+// Each profile is (bottom_start, bottom_end, top_start, top_end) in normalized height.
+struct CloudTypeUniform {
+    stratus: vec4<f32>,
+    cumulus: vec4<f32>,
+    cumulonimbus: vec4<f32>,
+    type_override: f32,
+    anvil_bias: f32,
+}
+
+struct ScatteringUniform {
+    multiple_scattering: u32,
+    octaves: u32,
+    extinction_attenuation: f32,
+    scattering_attenuation: f32,
+    eccentricity_attenuation: f32,
+    phase_function: u32,
+    g_forward: f32,
+    g_back: f32,
+    lobe_blend: f32,
+    droplet_diameter: f32,
+    powder_strength: f32,
+    powder_angle_dependence: f32,
+}
+
+// Must match `cloud::PhaseFunction`.
+const PHASE_HENYEY_GREENSTEIN: u32 = 0u;
+const PHASE_DUAL_LOBE_HENYEY_GREENSTEIN: u32 = 1u;
+const PHASE_CORNETTE_SHANKS: u32 = 2u;
+const PHASE_HENYEY_GREENSTEIN_DRAINE: u32 = 3u;
+
+// Must match `light::LightKind`.
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_DIRECTIONAL: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    // The direction the light travels in, for directional and spot lights.
+    direction: vec3<f32>,
+    intensity: f32,
+    color: vec3<f32>,
+    cos_inner_angle: f32,
+    cos_outer_angle: f32,
+}
+
+// Coefficients of the participating medium at a density of 1, per unit of distance.
+struct MediumUniform {
+    scattering: vec3<f32>,
+    absorption: vec3<f32>,
+}
+
+struct AmbientUniform {
+    top_color: vec3<f32>,
+    mode: u32,
+    bottom_color: vec3<f32>,
+    intensity: f32,
+    occlusion_strength: f32,
+}
+
+// Must match `cloud::AmbientMode`.
+const AMBIENT_CONSTANT: u32 = 0u;
+const AMBIENT_HEIGHT_GRADIENT: u32 = 1u;
+const AMBIENT_SKY: u32 = 2u;
+
+struct VolumeUniform {
+    mode: u32,
+    layer_bottom: f32,
+    layer_top: f32,
+    max_distance: f32,
+    weather_scale: f32,
+    planet_radius: f32,
+    camera_altitude: f32,
+    camera_radius: f32,
+}
+
+// Must match `cloud::VolumeMode`.
+const VOLUME_BOX: u32 = 0u;
+const VOLUME_LAYER: u32 = 1u;
+const VOLUME_SHELL: u32 = 2u;
+
+// Step sizes are fractions of `volume_size`.
+struct MarchUniform {
+    min_step: f32,
+    max_step: f32,
+    step_growth: f32,
+    coarse_step_scale: f32,
+    max_samples: u32,
+    light_step: f32,
+    light_max_samples: u32,
+    light_step_growth: f32,
+}
+
+// Samples stand for this fraction of the step they are taken every, when choosing the mip
+// level of the noise. The jitter spreads the samples of neighbouring pixels over the step.
+const STEP_FOOTPRINT_SCALE: f32 = 0.25;
+
+// The march towards a light only looks for volumes up to this many times the distance its
+// samples can cover, so that lit samples don't traverse the BVH all the way out of the scene.
+const LIGHT_MARCH_REACH: f32 = 2.0;
+
+// Consecutive empty samples after which the march goes back to coarse steps. The step back
+// after a coarse step is clamped below this, or the march could go back and forth without
+// moving on.
+const EMPTY_SAMPLES_BEFORE_COARSE: u32 = 6u;
+
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
 }
@@ -17,6 +194,13 @@ struct Ray {
     direction: vec3<f32>,
 }
 
+struct CloudOutput {
+    // Premultiplied by alpha.
+    @location(0) color: vec4<f32>,
+    // Guides the upsampling, see `scene_depth`.
+    @location(1) depth: f32,
+}
+
 @vertex
 fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
     var out: VertexOutput;
@@ -36,60 +220,440 @@ fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
 @group(0) @binding(0)
 var<uniform> camera: CameraUniform;
 @group(0) @binding(1)
-var<uniform> aabb: AABBUniform;
+var<storage, read> volumes: array<CloudVolume>;
 @group(0) @binding(2)
-var<uniform> screen_size: vec2<u32>;
+var<uniform> screen: ScreenUniform;
 @group(0) @binding(3)
-var<uniform> light_pos: vec3<f32>;
+var<storage, read> lights: array<Light>;
+@group(0) @binding(4)
+var<uniform> cloud: CloudUniform;
+@group(0) @binding(5)
+var<uniform> cloud_types: CloudTypeUniform;
+@group(0) @binding(6)
+var<uniform> scattering: ScatteringUniform;
+@group(0) @binding(7)
+var<uniform> medium: MediumUniform;
+@group(0) @binding(8)
+var<uniform> ambient: AmbientUniform;
+@group(0) @binding(9)
+var<uniform> temporal: TemporalUniform;
+@group(0) @binding(10)
+var<uniform> march: MarchUniform;
+@group(0) @binding(11)
+var<uniform> volume: VolumeUniform;
+@group(0) @binding(12)
+var<storage, read> bvh_nodes: array<BvhNode>;
 
 @group(1) @binding(0)
 var texture_cloud_noise: texture_3d<f32>;
 @group(1) @binding(1)
 var sampler_cloud_noise: sampler;
 @group(1) @binding(2)
-var texture_blue_noise: texture_2d<f32>;
-@group(1) @binding(3)
-var sampler_blue_noise: sampler;
+var texture_blue_noise: texture_3d<f32>;
+@group(1) @binding(4)
+var texture_cloud_detail: texture_3d<f32>;
+@group(1) @binding(5)
+var sampler_cloud_detail: sampler;
+@group(1) @binding(6)
+var texture_weather_map: texture_2d_array<f32>;
+@group(1) @binding(7)
+var sampler_weather_map: sampler;
+
+@group(2) @binding(0)
+var<uniform> atmosphere: AtmosphereUniform;
+@group(2) @binding(1)
+var texture_transmittance_lut: texture_2d<f32>;
+@group(2) @binding(2)
+var texture_sky_view_lut: texture_2d<f32>;
+@group(2) @binding(3)
+var sampler_atmosphere: sampler;
+
+// Depth of pixels that hit nothing. It has to fit in the 16 bit float of the cloud depth target.
+const FAR_DEPTH: f32 = 1e4;
+
+// Angular radius of the sun disk.
+const SUN_COS_RADIUS: f32 = 0.99996;
 
+// Renders the clouds into the offscreen cloud target, which may be smaller than the screen.
 @fragment
-fn fs_main(@builtin(position) frag_coord: vec4<f32>) -> @location(0) vec4<f32> {
-    let uv = vec2<f32>(
-        frag_coord.x / f32(screen_size.x),
-        frag_coord.y / f32(screen_size.y),
-    );
+fn fs_main(@builtin(position) frag_coord: vec4<f32>) -> CloudOutput {
+    let uv = frag_coord.xy / vec2<f32>(screen.cloud_size);
+    var ray = camera_ray(uv);
+
+    var out: CloudOutput;
+    out.depth = scene_depth(ray);
+
+    for (var i = 0u; i < arrayLength(&lights); i++) {
+        if(lights[i].kind == LIGHT_POINT && intersect_sphere(ray, lights[i].position, 0.3)) {
+            out.color = vec4(1.0);
+            return out;
+        }
+    }
+
+    var hits: RayVolumes;
+    var t_min: f32;
+    var t_max: f32;
+    if (!intersect_volume(ray, FAR_DEPTH, &hits, &t_min, &t_max)) {
+        out.color = vec4<f32>(0.0); // miss
+        return out;
+    }
+    // Don't march behind the camera when it is inside the volume.
+    t_min = max(t_min, 0.0);
+
+    // Angle between the rays of neighbouring pixels, which samples grow with over distance.
+    let pixel_angle = distance(camera_ray(uv + vec2<f32>(0.0, 1.0 / f32(screen.cloud_size.y))).direction, ray.direction);
+
+    out.color = raymarch_in_box(ray, &hits, t_min, t_max, blue_noise(frag_coord.xy), pixel_angle);
+    return out;
+}
+
+// Bound together with the cloud target, which is never used in the same pass.
+@group(3) @binding(3)
+var texture_density_grid: texture_2d<f32>;
+
+// Must match `density_grid::DENSITY_GRID_SIZE`.
+const DENSITY_GRID_SIZE: i32 = 16;
+// Must match `density_grid::DENSITY_GRIDS_PER_COLUMN`.
+const DENSITY_GRIDS_PER_COLUMN: i32 = 128;
+// Density samples per axis of every cell when rendering the density grid.
+const DENSITY_GRID_SAMPLES: i32 = 8;
+// Cells whose maximum density is below this are marched with larger steps.
+const SPARSE_CELL_DENSITY: f32 = 0.2;
+const SPARSE_STEP_SCALE: f32 = 2.0;
+
+// Renders the maximum density in every cell of the density grids. Every volume has a grid in
+// its local space. The cells of the 3D grid are laid out in a 2D texture, see
+// `density_grid_texel`. Every cell is sampled with a margin of half a cell, so that density
+// between the samples isn't missed.
+@fragment
+fn fs_density_grid(@builtin(position) frag_coord: vec4<f32>) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(frag_coord.xy);
+    let grid_size = vec2<i32>(DENSITY_GRID_SIZE * DENSITY_GRID_SIZE, DENSITY_GRID_SIZE);
+    let grid = texel / grid_size;
+    let index = u32(grid.y + grid.x * DENSITY_GRIDS_PER_COLUMN);
+    // The last column can be partly empty.
+    if (index >= arrayLength(&volumes)) {
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+    let grid_texel = texel % grid_size;
+    let cell = vec3<i32>(grid_texel.x % DENSITY_GRID_SIZE, grid_texel.y, grid_texel.x / DENSITY_GRID_SIZE);
+    let cell_size = 1.0 / f32(DENSITY_GRID_SIZE);
+    let start = -0.5 + (vec3<f32>(cell) - 0.5) * cell_size;
+
+    var max_density = 0.0;
+    for (var z = 0; z < DENSITY_GRID_SAMPLES; z++) {
+        for (var y = 0; y < DENSITY_GRID_SAMPLES; y++) {
+            for (var x = 0; x < DENSITY_GRID_SAMPLES; x++) {
+                let offset = (vec3<f32>(vec3<i32>(x, y, z)) + 0.5) / f32(DENSITY_GRID_SAMPLES) * 2.0;
+                let local = start + offset * cell_size;
+                max_density = max(max_density, volume_density(index, local, 0.0));
+            }
+        }
+    }
+
+    return vec4<f32>(max_density, 0.0, 0.0, 1.0);
+}
+
+@group(3) @binding(0)
+var texture_cloud_color: texture_2d<f32>;
+@group(3) @binding(1)
+var texture_cloud_depth: texture_2d<f32>;
+@group(3) @binding(2)
+var texture_cloud_history: texture_2d<f32>;
+
+// Blends the clouds of this frame with the clouds of the previous frames, reprojected to
+// where they are on the screen now. The history is clamped to the colors around the pixel
+// in this frame, which rejects history that doesn't belong there anymore.
+@fragment
+fn fs_temporal(@builtin(position) frag_coord: vec4<f32>) -> @location(0) vec4<f32> {
+    let coord = vec2<i32>(frag_coord.xy);
+    let max_coord = vec2<i32>(screen.cloud_size) - 1;
+    let current = textureLoad(texture_cloud_color, coord, 0);
+
+    if (temporal.enabled == 0u || temporal.history_valid == 0u) {
+        return current;
+    }
+
+    var min_color = current;
+    var max_color = current;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let neighbour = textureLoad(texture_cloud_color, clamp(coord + vec2<i32>(x, y), vec2<i32>(0), max_coord), 0);
+            min_color = min(min_color, neighbour);
+            max_color = max(max_color, neighbour);
+        }
+    }
+
+    let ray = camera_ray(frag_coord.xy / vec2<f32>(screen.cloud_size));
+    let depth = textureLoad(texture_cloud_depth, coord, 0).r;
+    // Where the clouds at this pixel were in the previous frame.
+    let prev_pos = ray.origin + ray.direction * depth - temporal.cloud_motion;
+    let prev_clip = camera.prev_view_proj * vec4<f32>(prev_pos, 1.0);
+    let prev_ndc = prev_clip.xy / prev_clip.w;
+    let prev_uv = vec2<f32>(prev_ndc.x * 0.5 + 0.5, 0.5 - prev_ndc.y * 0.5);
+    if (prev_clip.w <= 0.0 || any(prev_uv < vec2<f32>(0.0)) || any(prev_uv >= vec2<f32>(1.0))) {
+        return current; // it was off screen in the previous frame
+    }
+
+    let prev_coord = vec2<i32>(prev_uv * vec2<f32>(screen.cloud_size));
+    let history = clamp(textureLoad(texture_cloud_history, prev_coord, 0), min_color, max_color);
+    return mix(current, history, temporal.history_weight);
+}
+
+// Upsamples the accumulated clouds to the screen and composites them over the sky. Each of the four
+// closest cloud texels is weighted by how close its depth is to the depth of this pixel, so
+// the clouds don't bleed over the edges of the volume and the lights.
+@fragment
+fn fs_upsample(@builtin(position) frag_coord: vec4<f32>) -> @location(0) vec4<f32> {
+    let uv = frag_coord.xy / vec2<f32>(screen.size);
+    let ray = camera_ray(uv);
+    let depth = scene_depth(ray);
 
+    let cloud_coord = uv * vec2<f32>(screen.cloud_size) - 0.5;
+    let base = vec2<i32>(floor(cloud_coord));
+    let fraction = fract(cloud_coord);
+    let max_coord = vec2<i32>(screen.cloud_size) - 1;
+
+    var color = vec4<f32>(0.0);
+    var total_weight = 0.0;
+    for (var i = 0; i < 4; i++) {
+        let offset = vec2<i32>(i % 2, i / 2);
+        let coord = clamp(base + offset, vec2<i32>(0), max_coord);
+        let bilinear = mix(1.0 - fraction, fraction, vec2<f32>(offset));
+        let sample_depth = textureLoad(texture_cloud_depth, coord, 0).r;
+        let depth_weight = 1.0 / (1e-3 + abs(sample_depth - depth) / depth);
+        let weight = bilinear.x * bilinear.y * depth_weight;
+        color += textureLoad(texture_cloud_history, coord, 0) * weight;
+        total_weight += weight;
+    }
+    color /= max(total_weight, 1e-6);
+
+    let sky = sky_radiance(ray.direction);
+    return vec4<f32>(color.rgb + sky * (1.0 - color.a), 1.0);
+}
+
+// `uv` is in [0, 1] from the top left of the screen.
+fn camera_ray(uv: vec2<f32>) -> Ray {
     let ndc = vec2<f32>(
             uv.x * 2.0 - 1.0,
             1.0 - uv.y * 2.0
     );
     let clip = vec4<f32>(ndc, -1.0, 1.0);
     let world_pos = camera.view_proj_inv * clip;
-    var ray = Ray(
+    return Ray(
         camera.cam_pos, 
         normalize(world_pos.xyz / world_pos.w - camera.cam_pos)
     );
+}
 
-
-    if(intersect_sphere(ray, light_pos, 0.3)) {
-        return vec4(1.0);
+// Distance to the first surface the ray hits: a light or the boundary of the volume.
+fn scene_depth(ray: Ray) -> f32 {
+    var depth = FAR_DEPTH;
+    for (var i = 0u; i < arrayLength(&lights); i++) {
+        if(lights[i].kind == LIGHT_POINT && intersect_sphere(ray, lights[i].position, 0.3)) {
+            depth = min(depth, distance(ray.origin, lights[i].position));
+        }
     }
 
+    var hits: RayVolumes;
     var t_min: f32;
     var t_max: f32;
-    if (!intersect_aabb(ray, aabb, &t_min, &t_max)) {
-        return vec4<f32>(0.0); // miss
+    if (intersect_volume(ray, FAR_DEPTH, &hits, &t_min, &t_max) && t_max > 0.0) {
+        depth = min(depth, max(t_min, 0.0));
+    }
+    return max(depth, 1e-3);
+}
+
+// Intersects the ray with the volume of the clouds, see `cloud::VolumeMode`. `hits` is only
+// filled in for the boxes, and has to be passed along to everything sampling along the ray.
+// Boxes starting past `max_distance` are left out.
+fn intersect_volume(ray: Ray, max_distance: f32,
+                    hits: ptr<function, RayVolumes>,
+                    t_min_out: ptr<function, f32>, t_max_out: ptr<function, f32>) -> bool {
+    (*hits).count = 0u;
+    if (volume.mode == VOLUME_LAYER) {
+        return intersect_layer(ray, t_min_out, t_max_out);
+    }
+    if (volume.mode == VOLUME_SHELL) {
+        return intersect_shell(ray, t_min_out, t_max_out);
+    }
+    return intersect_volumes(ray, max_distance, hits, t_min_out, t_max_out);
+}
+
+// Finds the cloud volumes in front of the ray by traversing the BVH. The interval spans from
+// where it enters the first volume to where it leaves the last one, and the empty space
+// between them is skipped by `empty_cell_skip`. Nodes starting past `max_distance` are not
+// visited.
+fn intersect_volumes(ray: Ray, max_distance: f32,
+                     hits: ptr<function, RayVolumes>,
+                     t_min_out: ptr<function, f32>, t_max_out: ptr<function, f32>) -> bool {
+    // Without volumes, the root is an interior node without children.
+    let root = bvh_nodes[0];
+    if (root.volume == BVH_INTERIOR && root.first_child == 0u) {
+        return false;
+    }
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    stack[0] = 0u;
+    var stack_size = 1u;
+    while (stack_size > 0u) {
+        stack_size--;
+        let node = bvh_nodes[stack[stack_size]];
+
+        var t_min: f32;
+        var t_max: f32;
+        if (!intersect_aabb(ray, AABB(node.min, node.max), &t_min, &t_max) || t_max <= 0.0 || t_min > max_distance) {
+            continue;
+        }
+
+        if (node.volume != BVH_INTERIOR) {
+            if (intersect_aabb(local_ray(node.volume, ray), LOCAL_BOX, &t_min, &t_max) && t_max > 0.0) {
+                add_volume_interval(hits, VolumeInterval(t_min, t_max, node.volume));
+            }
+        } else {
+            stack[stack_size] = node.first_child;
+            stack[stack_size + 1u] = node.first_child + 1u;
+            stack_size += 2u;
+        }
+    }
+
+    if ((*hits).count == 0u) {
+        return false;
+    }
+
+    var t_max = 0.0;
+    for (var i = 0u; i < (*hits).count; i++) {
+        t_max = max(t_max, (*hits).intervals[i].t_max);
+    }
+    *t_min_out = (*hits).intervals[0].t_min;
+    *t_max_out = t_max;
+    return true;
+}
+
+// Inserts the interval into `hits`, keeping them ordered. When they are full, the interval
+// replaces the farthest one if it is closer.
+fn add_volume_interval(hits: ptr<function, RayVolumes>, interval: VolumeInterval) {
+    var i = (*hits).count;
+    if (i == MAX_RAY_VOLUMES) {
+        if (interval.t_min >= (*hits).intervals[i - 1u].t_min) {
+            return;
+        }
+        i--;
+    } else {
+        (*hits).count++;
+    }
+
+    while (i > 0u && (*hits).intervals[i - 1u].t_min > interval.t_min) {
+        (*hits).intervals[i] = (*hits).intervals[i - 1u];
+        i--;
     }
+    (*hits).intervals[i] = interval;
+}
 
-    // jittering
-    let blue_noise = blue_noise(uv); 
-    t_min += blue_noise * 0.1;
+fn interval_contains(interval: VolumeInterval, t: f32) -> bool {
+    return interval.t_min <= t && t <= interval.t_max;
+}
 
-    let color = raymarch_in_box(ray, t_min, t_max, 0.1);
-    return color;
+// The cube every cloud volume is in its local space.
+const LOCAL_BOX = AABB(vec3<f32>(-0.5), vec3<f32>(0.5));
+
+// The ray in the local space of volume `index`. The direction isn't normalized, so distances
+// along the local ray are the same as along the world ray.
+fn local_ray(index: u32, ray: Ray) -> Ray {
+    let world_to_local = volumes[index].world_to_local;
+    return Ray(
+        (world_to_local * vec4<f32>(ray.origin, 1.0)).xyz,
+        (world_to_local * vec4<f32>(ray.direction, 0.0)).xyz,
+    );
+}
+
+fn local_position(index: u32, pos: vec3<f32>) -> vec3<f32> {
+    return (volumes[index].world_to_local * vec4<f32>(pos, 1.0)).xyz;
+}
+
+// Intersects the ray with the first part of the shell in front of it. When the ray starts
+// below the shell, that is where it leaves the inner sphere, and otherwise where it enters
+// the inner sphere cuts it off. The planet hides everything behind it.
+fn intersect_shell(ray: Ray, t_min_out: ptr<function, f32>, t_max_out: ptr<function, f32>) -> bool {
+    // The planet center relative to the ray origin, which keeps the intersections precise
+    // even though the planet is huge compared to the clouds.
+    let origin = ray.origin - planet_center();
+    let outer = ray_sphere_interval(origin, ray.direction, volume.planet_radius + volume.layer_top);
+    if (outer.y <= 0.0) {
+        return false;
+    }
+
+    var t_min = outer.x;
+    var t_max = outer.y;
+
+    let inner = ray_sphere_interval(origin, ray.direction, volume.planet_radius + volume.layer_bottom);
+    if (inner.x > 0.0) {
+        t_max = min(t_max, inner.x);
+    } else if (inner.y > 0.0) {
+        t_min = max(t_min, inner.y);
+    }
+
+    let ground = ray_sphere_interval(origin, ray.direction, volume.planet_radius);
+    if (ground.x > 0.0) {
+        t_max = min(t_max, ground.x);
+    }
+
+    *t_min_out = t_min;
+    *t_max_out = t_max;
+    return max(t_min, 0.0) < t_max;
+}
+
+// Distances along the ray to where it enters and leaves the sphere of `radius` around the
+// origin, with `origin` being where the ray starts. Both are -1 if it misses. The distances are
+// found without subtracting the huge squared radius from the squared distance to the center,
+// see Haines et al., "Precision Improvements for Ray/Sphere Intersection", 2019.
+fn ray_sphere_interval(origin: vec3<f32>, direction: vec3<f32>, radius: f32) -> vec2<f32> {
+    let b = dot(origin, direction);
+    let to_closest = origin - b * direction;
+    let closest_distance = length(to_closest);
+    let discriminant = (radius - closest_distance) * (radius + closest_distance);
+    if (discriminant < 0.0) {
+        return vec2<f32>(-1.0);
+    }
+
+    let origin_distance = length(origin);
+    let c = (origin_distance - radius) * (origin_distance + radius);
+    let q = -b - select(-1.0, 1.0, b >= 0.0) * sqrt(discriminant);
+    let t0 = c / q;
+    let t1 = q;
+    return vec2<f32>(min(t0, t1), max(t0, t1));
+}
+
+fn planet_center() -> vec3<f32> {
+    return vec3<f32>(0.0, -volume.planet_radius, 0.0);
+}
+
+// Intersects the ray with the space between the two planes at the altitudes of the layer,
+// up to `volume.max_distance`. Like `intersect_aabb`, `t_min` is negative when the ray starts
+// inside the layer.
+fn intersect_layer(ray: Ray, t_min_out: ptr<function, f32>, t_max_out: ptr<function, f32>) -> bool {
+    var t_min = -1e10;
+    var t_max = 1e10;
+
+    if (ray.direction.y == 0.0) {
+        if (ray.origin.y < volume.layer_bottom || ray.origin.y > volume.layer_top) {
+            return false;
+        }
+    } else {
+        let t0 = (volume.layer_bottom - ray.origin.y) / ray.direction.y;
+        let t1 = (volume.layer_top - ray.origin.y) / ray.direction.y;
+        t_min = min(t0, t1);
+        t_max = max(t0, t1);
+    }
+
+    t_max = min(t_max, volume.max_distance);
+    *t_min_out = t_min;
+    *t_max_out = t_max;
+    return max(t_min, 0.0) < t_max;
 }
 
 fn intersect_aabb(ray: Ray,
-                  box: AABBUniform,
+                  box: AABB,
                   t_min_out: ptr<function, f32>, t_max_out: ptr<function, f32>) -> bool {
     var t_min = -1e10;
     var t_max = 1e10;
@@ -124,53 +688,330 @@ fn intersect_sphere(ray: Ray, sphere_center: vec3<f32>, radius: f32) -> bool {
     return discriminant > 0.0;
 }
 
-fn raymarch_in_box(ray: Ray, t_min: f32, t_max: f32, step: f32) -> vec4<f32> {
+// Marches with coarse steps until it finds a cloud, then goes back one step and continues
+// with fine steps, until it has seen nothing but empty space for a while. `jitter` in [0, 1]
+// offsets the first sample by a fraction of a step.
+fn raymarch_in_box(ray: Ray,
+                   hits: ptr<function, RayVolumes>,
+                   t_min: f32, t_max: f32, jitter: f32, pixel_angle: f32) -> vec4<f32> {
     var color = vec3<f32>(0.0);
-    var transmittance = 1.0;
+    var transmittance = vec3<f32>(1.0);
+    let sky = sky_ambient();
 
-    for(var t = t_min; t < t_max; t += step) {
+    var t = t_min + march_step(volume_size(hits, t_min), t_min) * jitter;
+    var coarse = true;
+    var empty_samples = 0u;
+    var samples = 0u;
+    while (t < t_max && samples < march.max_samples) {
         let pos = ray.origin + ray.direction * t;
-        let density = sample_density(pos);
 
-        transmittance *= beer_lambert(step, density);
-        if (transmittance < 0.01) {
+        let max_density = density_grid_max(hits, pos, t);
+        let size = volume_size(hits, t);
+        var step = march_step(size, t);
+        if (max_density <= 0.0) {
+            t += empty_cell_skip(ray, hits, t, step);
+            continue;
+        }
+        if (max_density < SPARSE_CELL_DENSITY) {
+            step *= SPARSE_STEP_SCALE;
+        }
+
+        let sample = sample_medium(hits, pos, t, max(t * pixel_angle, step * STEP_FOOTPRINT_SCALE));
+        let density = sample.density;
+        samples++;
+
+        if (coarse) {
+            if (density > 0.0) {
+                // Go back to where the cloud may have started, but not past the empty samples
+                // that switched to coarse steps.
+                coarse = false;
+                empty_samples = 0u;
+                let step_back = min(march.coarse_step_scale, f32(EMPTY_SAMPLES_BEFORE_COARSE - 1u));
+                t = max(t - step * step_back, t_min);
+            } else {
+                t += step * march.coarse_step_scale;
+            }
+            continue;
+        }
+
+        if (density <= 0.0) {
+            empty_samples++;
+            coarse = empty_samples >= EMPTY_SAMPLES_BEFORE_COARSE;
+            t += step;
+            continue;
+        }
+        empty_samples = 0u;
+
+        transmittance *= beer_lambert(sample.extinction * step);
+        if (all(transmittance < vec3<f32>(0.01))) {
             break;
         }
 
         if(density > 0.01) {
-            let distance_to_light = distance(pos, light_pos);
-            let ray_to_light = Ray(pos, (light_pos - pos) / distance_to_light);
-            let light = raymarch_to_light(ray_to_light, 0.1);
+            let scattering_coefficient = sample.scattering;
+            let height = normalized_height(hits, pos, t);
+            color += step * scattering_coefficient * transmittance * ambient_light(height, sky);
+            for (var i = 0u; i < arrayLength(&lights); i++) {
+                color += step * scattering_coefficient * transmittance * in_scattering(ray, pos, lights[i], size);
+            }
+        }
 
-            let phase = henyey_greenstein(dot(-ray.direction, ray_to_light.direction), 0.6);
-            color += vec3<f32>(step * density * light * transmittance * phase);
-        }        
+        t += step;
     }
 
-    
-    return vec4<f32>(vec3<f32>(color), 1.0 - transmittance);
+    let alpha = 1.0 - dot(transmittance, vec3<f32>(1.0 / 3.0));
+    return vec4<f32>(vec3<f32>(color), alpha);
 }
 
-fn raymarch_to_light(ray: Ray, step: f32) -> f32 {
+// Fine step size at a distance `t` from the camera, in a volume of `size`. Far away clouds
+// cover fewer pixels, so they are marched with larger steps.
+fn march_step(size: f32, t: f32) -> f32 {
+    return size * clamp(march.min_step + march.step_growth * t / size, march.min_step, march.max_step);
+}
+
+// Smallest extent of the volume at `t` along the ray `hits` belongs to, which the step sizes
+// are relative to. Where volumes overlap the smallest one counts, and between volumes the next
+// one along the ray.
+fn volume_size(hits: ptr<function, RayVolumes>, t: f32) -> f32 {
+    if (volume.mode != VOLUME_BOX) {
+        return volume.layer_top - volume.layer_bottom;
+    }
+    var size = 1e10;
+    for (var i = 0u; i < (*hits).count; i++) {
+        let interval = (*hits).intervals[i];
+        // The intervals are sorted by where they start, so the rest start even later.
+        if (interval.t_min > t && size < 1e10) {
+            break;
+        }
+        if (interval.t_max > t) {
+            let extent = volumes[interval.index].size;
+            size = min(size, min(extent.x, min(extent.y, extent.z)));
+        }
+    }
+    return size;
+}
+
+// Returns the light arriving at `pos` from `light` and scattered towards the camera. `size` is
+// the size of the volume `pos` is in, see `volume_size`.
+fn in_scattering(ray: Ray, pos: vec3<f32>, light: Light, size: f32) -> vec3<f32> {
+    var ray_to_light: Ray;
+    var distance_to_light: f32;
+    var attenuation = 1.0;
+    var color = light.color;
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        // The sun is infinitely far away, so march to the boundary of the volume.
+        ray_to_light = Ray(pos, -normalize(light.direction));
+        distance_to_light = 1e10;
+        // Sunlight is reddened by the atmosphere on its way to the clouds.
+        color *= atmosphere_transmittance(ray_to_light.direction);
+    } else {
+        distance_to_light = distance(pos, light.position);
+        ray_to_light = Ray(pos, (light.position - pos) / distance_to_light);
+        attenuation = 1.0 / (distance_to_light * distance_to_light);
+
+        if (light.kind == LIGHT_SPOT) {
+            let cos_angle = dot(-ray_to_light.direction, normalize(light.direction));
+            attenuation *= smoothstep(light.cos_outer_angle, light.cos_inner_angle, cos_angle);
+        }
+    }
+
+    if (attenuation <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+
+    let optical_depth = raymarch_to_light(ray_to_light, distance_to_light, size);
+    let cos_theta = dot(-ray.direction, ray_to_light.direction);
+    let scattered = scattered_light(optical_depth, cos_theta);
+    return scattered * attenuation * color * light.intensity;
+}
+
+// Returns the optical depth along the ray until it leaves the volume or reaches the light.
+// The steps are relative to `size`, the size of the volume the ray starts in, and grow by
+// `march.light_step_growth` with every sample.
+fn raymarch_to_light(ray: Ray, distance_to_light: f32, size: f32) -> vec3<f32> {
+    var step = march.light_step * size;
+    // The distance the samples cover, the sum of the growing steps.
+    let growth = march.light_step_growth;
+    let samples_distance = select(
+        step * (pow(growth, f32(march.light_max_samples)) - 1.0) / (growth - 1.0),
+        step * f32(march.light_max_samples),
+        growth == 1.0);
+    let max_distance = min(distance_to_light, samples_distance * LIGHT_MARCH_REACH);
+
+    var hits: RayVolumes;
     var t_min: f32;
     var t_max: f32;
-    intersect_aabb(ray, aabb, &t_min, &t_max);
+    intersect_volume(ray, max_distance, &hits, &t_min, &t_max);
+    t_max = min(t_max, max_distance);
+
+    // The last octave is the one attenuated the least, so keep marching until even it is opaque.
+    let min_extinction = pow(scattering.extinction_attenuation, f32(scattering_octaves() - 1u));
 
-    var transmittance = 1.0;
-    for(var t = 0.0; t < t_max; t += step) {
+    var optical_depth = vec3<f32>(0.0);
+    var t = 0.0;
+    var samples = 0u;
+    while (t < t_max && samples < march.light_max_samples) {
         let pos = ray.origin + ray.direction * t;
-        let density = sample_density(pos);
-        transmittance *= beer_lambert(step, density);
-        if (transmittance < 0.01) {
+        if (density_grid_max(&hits, pos, t) <= 0.0) {
+            t += empty_cell_skip(ray, &hits, t, step);
+            continue;
+        }
+
+        optical_depth += step * sample_medium(&hits, pos, t, step * STEP_FOOTPRINT_SCALE).extinction;
+        samples++;
+        if (all(beer_lambert(optical_depth * min_extinction) < vec3<f32>(0.01))) {
             break;
         }
+        t += step;
+        step *= growth;
+    }
+
+    return optical_depth;
+}
+
+// Cell of the density grid of a volume containing the point `local` of its local space.
+fn density_grid_cell(local: vec3<f32>) -> vec3<i32> {
+    let uvw = local + 0.5;
+    return clamp(vec3<i32>(floor(uvw * f32(DENSITY_GRID_SIZE))), vec3<i32>(0), vec3<i32>(DENSITY_GRID_SIZE - 1));
+}
+
+// Texel of `cell` of the density grid of the volume at `index`. Every grid is a row of its
+// horizontal slices, with x + z * DENSITY_GRID_SIZE as the column and y as the row. The rows
+// of the volumes are stacked on top of each other, DENSITY_GRIDS_PER_COLUMN at a time, and
+// the stacks are next to each other.
+fn density_grid_texel(index: u32, cell: vec3<i32>) -> vec2<i32> {
+    let grid = vec2<i32>(i32(index) / DENSITY_GRIDS_PER_COLUMN, i32(index) % DENSITY_GRIDS_PER_COLUMN);
+    return grid * vec2<i32>(DENSITY_GRID_SIZE * DENSITY_GRID_SIZE, DENSITY_GRID_SIZE)
+        + vec2<i32>(cell.x + cell.z * DENSITY_GRID_SIZE, cell.y);
+}
+
+// Number of volumes that have a grid, which is less than the number of volumes if the texture
+// couldn't be made large enough.
+fn density_grid_capacity() -> u32 {
+    let columns = textureDimensions(texture_density_grid).x / u32(DENSITY_GRID_SIZE * DENSITY_GRID_SIZE);
+    return columns * u32(DENSITY_GRIDS_PER_COLUMN);
+}
+
+// Upper bound of the density in the grid cells containing `pos`, which is at `t` along the ray
+// `hits` belongs to. The volumes overlapping there add up, just like their densities do.
+fn density_grid_max(hits: ptr<function, RayVolumes>, pos: vec3<f32>, t: f32) -> f32 {
+    // The grid only covers the boxes, so anywhere in the layer could have clouds.
+    if (volume.mode != VOLUME_BOX) {
+        return 1.0;
+    }
+
+    let capacity = density_grid_capacity();
+    var max_density = 0.0;
+    for (var i = 0u; i < (*hits).count; i++) {
+        let interval = (*hits).intervals[i];
+        if (interval_contains(interval, t)) {
+            // Volumes without a grid could have clouds anywhere.
+            if (interval.index >= capacity) {
+                max_density += 1.0;
+                continue;
+            }
+            let cell = density_grid_cell(local_position(interval.index, pos));
+            max_density += textureLoad(texture_density_grid, density_grid_texel(interval.index, cell), 0).r;
+        }
     }
+    return max_density;
+}
 
-    return transmittance;
+// How far to move along the ray from `t`, which is in empty cells of the density grids or
+// outside of every volume, to the first sample past the cells or in the next volume. Whole
+// steps are taken, so the jittered samples stay in place.
+fn empty_cell_skip(ray: Ray, hits: ptr<function, RayVolumes>, t: f32, step: f32) -> f32 {
+    let pos = ray.origin + ray.direction * t;
+    var distance = 1e10;
+    for (var i = 0u; i < (*hits).count; i++) {
+        let interval = (*hits).intervals[i];
+        if (interval_contains(interval, t)) {
+            distance = min(distance, cell_exit_distance(local_ray(interval.index, Ray(pos, ray.direction))));
+        } else if (interval.t_min > t) {
+            distance = min(distance, interval.t_min - t);
+        }
+    }
+    return max(ceil(distance / step), 1.0) * step;
+}
+
+// Distance along the local `ray` to where it leaves the grid cell its origin is in.
+fn cell_exit_distance(ray: Ray) -> f32 {
+    let cell_size = 1.0 / f32(DENSITY_GRID_SIZE);
+    let cell_min = -0.5 + vec3<f32>(density_grid_cell(ray.origin)) * cell_size;
+    let exit_planes = cell_min + select(vec3<f32>(0.0), vec3<f32>(cell_size), ray.direction > vec3<f32>(0.0));
+    let distances = select(
+        (exit_planes - ray.origin) / ray.direction,
+        vec3<f32>(1e10),
+        ray.direction == vec3<f32>(0.0),
+    );
+    return min(distances.x, min(distances.y, distances.z));
 }
 
-fn beer_lambert(distance: f32, density: f32) -> f32 {
-    return exp(-distance * density * 3.2);
+// Approximates multiple scattering by summing octaves of single scattering. Each octave
+// has its extinction, scattering and phase eccentricity attenuated by another factor of
+// a, b and c, which lets light reach deeper into the cloud with a more isotropic phase.
+fn scattered_light(optical_depth: vec3<f32>, cos_theta: f32) -> vec3<f32> {
+    var light = vec3<f32>(0.0);
+    var extinction_scale = 1.0;
+    var scattering_scale = 1.0;
+    var eccentricity_scale = 1.0;
+
+    for (var i = 0u; i < scattering_octaves(); i++) {
+        let transmittance = beer_lambert(optical_depth * extinction_scale);
+        let phase = phase_function(cos_theta, eccentricity_scale);
+        light += scattering_scale * transmittance * phase;
+
+        extinction_scale *= scattering.extinction_attenuation;
+        scattering_scale *= scattering.scattering_attenuation;
+        eccentricity_scale *= scattering.eccentricity_attenuation;
+    }
+
+    return light * powder(optical_depth, cos_theta);
+}
+
+// The "powder sugar" effect: light only gets scattered towards the viewer after it has
+// travelled some way into the cloud, so the thin edges facing the light look darker.
+// See Schneider, "The Real-Time Volumetric Cloudscapes of Horizon Zero Dawn", 2015.
+fn powder(optical_depth: vec3<f32>, cos_theta: f32) -> vec3<f32> {
+    let powder = 1.0 - beer_lambert(optical_depth * 2.0);
+    // `cos_theta` is 1 when the light is right behind the viewer.
+    let angle_factor = mix(1.0, saturate(cos_theta * 0.5 + 0.5), scattering.powder_angle_dependence);
+    return mix(vec3<f32>(1.0), powder, scattering.powder_strength * angle_factor);
+}
+
+// With multiple scattering disabled, only the first octave, i.e. single scattering, is left.
+fn scattering_octaves() -> u32 {
+    return select(1u, max(scattering.octaves, 1u), scattering.multiple_scattering != 0u);
+}
+
+fn beer_lambert(optical_depth: vec3<f32>) -> vec3<f32> {
+    return exp(-optical_depth);
+}
+
+// Extinction coefficient of the medium at a density of 1.
+fn extinction() -> vec3<f32> {
+    return medium.scattering + medium.absorption;
+}
+
+// All the phase functions here are normalized to integrate to 1 over cos_theta in [-1, 1].
+// `eccentricity_scale` is the eccentricity attenuation of the current scattering octave.
+fn phase_function(cos_theta: f32, eccentricity_scale: f32) -> f32 {
+    switch (scattering.phase_function) {
+        case PHASE_DUAL_LOBE_HENYEY_GREENSTEIN: {
+            let forward = henyey_greenstein(cos_theta, scattering.g_forward * eccentricity_scale);
+            let back = henyey_greenstein(cos_theta, scattering.g_back * eccentricity_scale);
+            return mix(back, forward, scattering.lobe_blend);
+        }
+        case PHASE_CORNETTE_SHANKS: {
+            return cornette_shanks(cos_theta, scattering.g_forward * eccentricity_scale);
+        }
+        case PHASE_HENYEY_GREENSTEIN_DRAINE: {
+            return henyey_greenstein_draine(cos_theta, scattering.droplet_diameter, eccentricity_scale);
+        }
+        default: {
+            return henyey_greenstein(cos_theta, scattering.g_forward * eccentricity_scale);
+        }
+    }
 }
 
 fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
@@ -178,11 +1019,290 @@ fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
     return (1.0 - g2) / pow(1.0 + g2 - 2.0 * g * cos_theta, 1.5) * 0.5;
 }
 
-fn sample_density(pos: vec3<f32>) -> f32 {
-    let uvw = (pos - aabb.min) / (aabb.max - aabb.min);
-    return textureSample(texture_cloud_noise, sampler_cloud_noise, uvw).r;
+fn cornette_shanks(cos_theta: f32, g: f32) -> f32 {
+    let g2 = g * g;
+    let numerator = (1.0 - g2) * (1.0 + cos_theta * cos_theta);
+    let denominator = (2.0 + g2) * pow(1.0 + g2 - 2.0 * g * cos_theta, 1.5);
+    return numerator / denominator * 0.75;
 }
 
-fn blue_noise(uv: vec2<f32>) -> f32 {
-    return textureSample(texture_blue_noise, sampler_blue_noise, uv).r;
-}
\ No newline at end of file
+fn draine(cos_theta: f32, g: f32, alpha: f32) -> f32 {
+    let g2 = g * g;
+    let henyey_greenstein_term = (1.0 - g2) / pow(1.0 + g2 - 2.0 * g * cos_theta, 1.5);
+    let draine_term = (1.0 + alpha * cos_theta * cos_theta) / (1.0 + alpha * (1.0 + 2.0 * g2) / 3.0);
+    return henyey_greenstein_term * draine_term * 0.5;
+}
+
+// Fit of the Mie phase function of water droplets of the given diameter in micrometers.
+// See Jendersie and d'Eon, "An Approximate Mie Scattering Function for Fog and Cloud Rendering".
+fn henyey_greenstein_draine(cos_theta: f32, diameter: f32, eccentricity_scale: f32) -> f32 {
+    let d = clamp(diameter, 5.0, 50.0);
+    let g_henyey_greenstein = exp(-0.0990567 / (d - 1.67154)) * eccentricity_scale;
+    let g_draine = exp(-2.20679 / (d + 3.91029) - 0.428934) * eccentricity_scale;
+    let alpha = exp(3.62489 - 8.29288 / (d + 5.52825));
+    let weight = exp(-0.599085 / (d - 0.641583) - 0.665888);
+
+    return mix(
+        henyey_greenstein(cos_theta, g_henyey_greenstein),
+        draine(cos_theta, g_draine, alpha),
+        weight,
+    );
+}
+
+// What the clouds at a point look like, apart from the noise.
+struct CloudSite {
+    // r: coverage, g: cloud type, b: density
+    weather: vec4<f32>,
+    // Height in the volume, 0 at the bottom and 1 at the top.
+    height: f32,
+    // Cloud type in [0, 1], or negative to take it from the weather map.
+    type_override: f32,
+    density_scale: f32,
+    erosion_scale: f32,
+}
+
+// The medium at a point, with the coefficients already scaled by the density there.
+struct MediumSample {
+    density: f32,
+    scattering: vec3<f32>,
+    extinction: vec3<f32>,
+}
+
+// The medium at `pos`, which is at `t` along the ray `hits` belongs to. `footprint` is the
+// world space size the sample stands for. The noise is sampled from the mip level whose texels
+// are that large, so large samples don't alias. Where cloud volumes overlap, their densities
+// and coefficients add up.
+fn sample_medium(hits: ptr<function, RayVolumes>, pos: vec3<f32>, t: f32, footprint: f32) -> MediumSample {
+    if (volume.mode != VOLUME_BOX) {
+        let site = CloudSite(sample_weather(pos), normalized_height(hits, pos, t), cloud_types.type_override, 1.0, 1.0);
+        let density = cloud_density(pos, site, footprint);
+        return MediumSample(density, medium.scattering * density, extinction() * density);
+    }
+
+    var sample = MediumSample(0.0, vec3<f32>(0.0), vec3<f32>(0.0));
+    for (var i = 0u; i < (*hits).count; i++) {
+        let interval = (*hits).intervals[i];
+        if (interval_contains(interval, t)) {
+            let cloud_volume = volumes[interval.index];
+            let density = volume_density(interval.index, local_position(interval.index, pos), footprint);
+            let scattering = medium.scattering * cloud_volume.scattering_scale * density;
+            sample.density += density;
+            sample.scattering += scattering;
+            sample.extinction += scattering + medium.absorption * cloud_volume.absorption_scale * density;
+        }
+    }
+    return sample;
+}
+
+// Density of volume `index` at `local` in the local space of the volume. The weather map covers
+// the volume once. The noise moves with the volume, so moving it doesn't change its clouds or
+// its density grid, and every volume starts somewhere else in the noise, so volumes of the same
+// size don't look the same.
+fn volume_density(index: u32, local: vec3<f32>, footprint: f32) -> f32 {
+    let cloud_volume = volumes[index];
+    let type_override = select(cloud_types.type_override, cloud_volume.type_override, cloud_volume.type_override >= 0.0);
+    let site = CloudSite(
+        sample_weather_map(local.xz + 0.5, cloud_volume.weather_layer),
+        local.y + 0.5,
+        type_override,
+        cloud_volume.density_scale,
+        cloud_volume.erosion_scale,
+    );
+    let noise_offset = fract(f32(index) * vec3<f32>(0.618034, 0.414214, 0.732051)) * cloud.noise_scale;
+    return cloud_density(local * cloud_volume.size + noise_offset, site, footprint);
+}
+
+// Density of the clouds described by `site`, with the noise sampled at `pos`.
+fn cloud_density(pos: vec3<f32>, site: CloudSite, footprint: f32) -> f32 {
+    let weather = site.weather;
+    let height = site.height;
+    let cloud_type = select(weather.g, site.type_override, site.type_override >= 0.0);
+
+    let height_gradient = height_gradient(height, cloud_type);
+    let coverage = anvil_coverage(weather.r, height, cloud_type);
+    if (coverage <= 0.0 || height_gradient <= 0.0) {
+        return 0.0;
+    }
+
+    // The noise repeats in world space, independent of the size of the volume.
+    let noise_uvw = pos / cloud.noise_scale;
+    let noise_lod = noise_lod(footprint, textureDimensions(texture_cloud_noise).x, 1.0);
+    let noise = textureSampleLevel(texture_cloud_noise, sampler_cloud_noise, noise_uvw, noise_lod);
+
+    // Build the base shape by eroding the Perlin-Worley noise with the low frequency Worley fBm.
+    let worley_fbm = noise.g * 0.625 + noise.b * 0.25 + noise.a * 0.125;
+    let base_shape = saturate(remap(noise.r, worley_fbm - 1.0, 1.0, 0.0, 1.0));
+    let base_shape_stretched = saturate(remap(base_shape, BASE_SHAPE_MIN, 1.0, 0.0, 1.0)) * height_gradient;
+
+    // Only the parts of the base shape above (1 - coverage) survive, so a coverage of 0
+    // means no clouds at all and a coverage of 1 keeps the whole base shape.
+    let base_density = saturate(remap(base_shape_stretched, 1.0 - coverage, 1.0, 0.0, 1.0)) * coverage;
+    if (base_density <= 0.0) {
+        return 0.0;
+    }
+
+    // Erode the edges of the base shape with the high frequency detail noise.
+    // Thin parts of the base get carved away, while the dense cores are barely affected.
+    let detail_lod = noise_lod(footprint, textureDimensions(texture_cloud_detail).x, cloud.detail_scale);
+    let detail = textureSampleLevel(texture_cloud_detail, sampler_cloud_detail, noise_uvw * cloud.detail_scale, detail_lod).r;
+    let density = saturate(remap(base_density, detail * cloud.erosion_strength * site.erosion_scale, 1.0, 0.0, 1.0));
+
+    return density * weather.b * cloud.density_multiplier * site.density_scale;
+}
+
+// Mip level of a noise texture with `dimension` texels, tiled `scale` times across one
+// `cloud.noise_scale`, whose texels are `footprint` large.
+fn noise_lod(footprint: f32, dimension: u32, scale: f32) -> f32 {
+    let texel_size = cloud.noise_scale / (f32(dimension) * scale);
+    return max(log2(footprint / texel_size), 0.0);
+}
+
+// The weather map repeats across the layer. The shell is covered from all three axes, blended
+// by how much it faces them, so the map doesn't stretch where the shell curves away. Both use
+// the first layer of the weather map.
+fn sample_weather(pos: vec3<f32>) -> vec4<f32> {
+    if (volume.mode == VOLUME_SHELL) {
+        let p = (pos - planet_center()) / volume.weather_scale;
+        var weights = pow(abs(normalize(p)), vec3<f32>(4.0));
+        weights /= weights.x + weights.y + weights.z;
+        return weights.x * sample_weather_map(p.yz, 0u)
+            + weights.y * sample_weather_map(p.xz, 0u)
+            + weights.z * sample_weather_map(p.xy, 0u);
+    }
+    return sample_weather_map(pos.xz / volume.weather_scale, 0u);
+}
+
+// Layers past the last one of the weather map are clamped to it.
+fn sample_weather_map(uv: vec2<f32>, layer: u32) -> vec4<f32> {
+    let last_layer = textureNumLayers(texture_weather_map) - 1u;
+    return textureSampleLevel(texture_weather_map, sampler_weather_map, uv, min(layer, last_layer), 0.0);
+}
+
+// Altitude of `pos` above the planet. Subtracting the planet radius from the distance to its
+// center would lose most of the precision, so the altitude is found relative to the camera,
+// from |c + d| - |c| = (2 c.d + d.d) / (|c + d| + |c|), with c from the planet center to the
+// camera and d from the camera to `pos`.
+fn shell_altitude(pos: vec3<f32>) -> f32 {
+    let to_camera = camera.cam_pos - planet_center();
+    let offset = pos - camera.cam_pos;
+    let up = normalize(to_camera);
+    let numerator = 2.0 * volume.camera_radius * dot(up, offset) + dot(offset, offset);
+    let denominator = length(to_camera + offset) + volume.camera_radius;
+    return volume.camera_altitude + numerator / denominator;
+}
+
+// Height of `pos`, which is at `t` along the ray `hits` belongs to, inside the volume. 0 at
+// the bottom and 1 at the top.
+fn normalized_height(hits: ptr<function, RayVolumes>, pos: vec3<f32>, t: f32) -> f32 {
+    if (volume.mode == VOLUME_LAYER) {
+        return (pos.y - volume.layer_bottom) / (volume.layer_top - volume.layer_bottom);
+    }
+    if (volume.mode == VOLUME_SHELL) {
+        let altitude = shell_altitude(pos);
+        return (altitude - volume.layer_bottom) / (volume.layer_top - volume.layer_bottom);
+    }
+    for (var i = 0u; i < (*hits).count; i++) {
+        let interval = (*hits).intervals[i];
+        if (interval_contains(interval, t)) {
+            return local_position(interval.index, pos).y + 0.5;
+        }
+    }
+    return 0.0;
+}
+
+// Blends the vertical profiles of the two cloud types closest to `cloud_type`.
+fn height_gradient(height: f32, cloud_type: f32) -> f32 {
+    let stratus = height_profile(height, cloud_types.stratus);
+    let cumulus = height_profile(height, cloud_types.cumulus);
+    let cumulonimbus = height_profile(height, cloud_types.cumulonimbus);
+
+    if (cloud_type < 0.5) {
+        return mix(stratus, cumulus, cloud_type * 2.0);
+    }
+    return mix(cumulus, cumulonimbus, cloud_type * 2.0 - 1.0);
+}
+
+fn height_profile(height: f32, profile: vec4<f32>) -> f32 {
+    let bottom = saturate(remap(height, profile.x, profile.y, 0.0, 1.0));
+    let top = saturate(remap(height, profile.z, profile.w, 1.0, 0.0));
+    return bottom * top;
+}
+
+// Spreads the coverage of cumulonimbus near the top of the volume, which forms the anvil.
+fn anvil_coverage(coverage: f32, height: f32, cloud_type: f32) -> f32 {
+    let anvil = saturate(remap(cloud_type, 0.5, 1.0, 0.0, 1.0)) * cloud_types.anvil_bias;
+    let exponent = mix(1.0, 0.5, anvil * saturate(remap(height, 0.7, 0.8, 0.0, 1.0)));
+    return pow(coverage, exponent);
+}
+
+fn remap(value: f32, old_min: f32, old_max: f32, new_min: f32, new_max: f32) -> f32 {
+    return new_min + (value - old_min) / (old_max - old_min) * (new_max - new_min);
+}
+
+// Fractional part of the golden ratio. Adding multiples of it gives a sequence that stays
+// well distributed in [0, 1] however many elements are taken.
+const GOLDEN_RATIO_FRACTION: f32 = 0.61803398875;
+
+// Tiles the blue noise over the screen one texel per pixel and takes the next slice every
+// frame. After all the slices were used, they are shifted (Cranley-Patterson rotation) so
+// the temporal accumulation keeps converging.
+fn blue_noise(frag_coord: vec2<f32>) -> f32 {
+    let size = textureDimensions(texture_blue_noise);
+    let coord = vec3<u32>(vec2<u32>(frag_coord) % size.xy, temporal.frame % size.z);
+    let noise = textureLoad(texture_blue_noise, coord, 0).r;
+    let cycle = temporal.frame / size.z;
+    return fract(noise + f32(cycle % 256u) * GOLDEN_RATIO_FRACTION);
+}
+
+// Radiance of the sky behind the clouds, including the sun disk.
+fn sky_radiance(direction: vec3<f32>) -> vec3<f32> {
+    var radiance = sample_sky_view(direction);
+    if (dot(direction, atmosphere.sun_direction) > SUN_COS_RADIUS) {
+        radiance += atmosphere.sun_illuminance * atmosphere_transmittance(direction);
+    }
+    return radiance;
+}
+
+// Ambient light at `normalized_height` in the volume, where `sky` is the result of
+// `sky_ambient`. The bottom of the volume receives less light since the cloud above occludes
+// most of the sky.
+fn ambient_light(normalized_height: f32, sky: vec3<f32>) -> vec3<f32> {
+    let height = saturate(normalized_height);
+
+    var color: vec3<f32>;
+    switch (ambient.mode) {
+        case AMBIENT_CONSTANT: {
+            color = ambient.top_color;
+        }
+        case AMBIENT_HEIGHT_GRADIENT: {
+            color = mix(ambient.bottom_color, ambient.top_color, height);
+        }
+        default: {
+            color = mix(ambient.bottom_color, sky, height);
+        }
+    }
+
+    let occlusion = mix(1.0 - ambient.occlusion_strength, 1.0, height);
+    return color * occlusion * ambient.intensity;
+}
+
+// Isotropic approximation of the sky light reaching the clouds, averaged over a few
+// directions of the upper hemisphere. The lower hemisphere is assumed to be dark ground.
+fn sky_ambient() -> vec3<f32> {
+    var radiance = sample_sky_view(vec3<f32>(0.0, 1.0, 0.0));
+    for (var i = 0; i < 4; i++) {
+        let azimuth = f32(i) * PI * 0.5;
+        radiance += sample_sky_view(vec3<f32>(0.866 * cos(azimuth), 0.5, 0.866 * sin(azimuth)));
+    }
+    return radiance / 5.0 * 0.5;
+}
+
+fn sample_sky_view(direction: vec3<f32>) -> vec3<f32> {
+    return textureSampleLevel(texture_sky_view_lut, sampler_atmosphere, sky_view_lut_uv(direction), 0.0).rgb;
+}
+
+// Transmittance of the atmosphere from the camera to space in the given direction.
+fn atmosphere_transmittance(direction: vec3<f32>) -> vec3<f32> {
+    let uv = transmittance_lut_uv(atmosphere, atmosphere.camera_altitude, direction.y);
+    return textureSampleLevel(texture_transmittance_lut, sampler_atmosphere, uv, 0.0).rgb;
+}
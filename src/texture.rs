@@ -1,27 +1,15 @@
 use std::path::Path;
 
-use noise::{NoiseFn, Worley};
-
 pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
-pub fn load_texture_2d_gray<P: AsRef<Path>>(
+pub fn load_texture_2d_rgba<P: AsRef<Path>>(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     path: &P,
 ) -> image::ImageResult<wgpu::Texture> {
-    let image = image::open(path)?.to_luma32f();
+    let image = image::open(path)?.to_rgba8();
     let (width, height) = image.dimensions();
 
-    // Get data of &[u8]
-    let raw_data = image.into_raw();
-    let raw_data = bytemuck::cast_slice(&raw_data);
-
-    let texture_size = wgpu::Extent3d {
-        width,
-        height,
-        depth_or_array_layers: 1,
-    };
-
     let file_name = path
         .as_ref()
         .file_name()
@@ -29,98 +17,812 @@ pub fn load_texture_2d_gray<P: AsRef<Path>>(
         .to_string_lossy()
         .to_string();
 
+    Ok(create_texture_2d_rgba(
+        device,
+        queue,
+        width,
+        height,
+        1,
+        &image.into_raw(),
+        Some(format!("Rgba8 Texture {}", file_name.as_str()).as_str()),
+    ))
+}
+
+/// `data` holds `layers` images one after another, which become the layers of the texture.
+/// The texture is viewed as an array, but GL guesses the view from the number of layers, so a
+/// single image is repeated in a second layer.
+pub fn create_texture_2d_rgba(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    width: u32,
+    height: u32,
+    layers: u32,
+    data: &[u8],
+    label: Option<&str>,
+) -> wgpu::Texture {
+    let texture_size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: layers,
+    };
+
     let texture = device.create_texture(&wgpu::TextureDescriptor {
-        label: Some(format!("Luma32f (Gray) Texture {}", file_name.as_str()).as_str()),
-        size: texture_size,
+        label,
+        size: wgpu::Extent3d {
+            depth_or_array_layers: layers.max(2),
+            ..texture_size
+        },
         mip_level_count: 1,
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
-        format: wgpu::TextureFormat::R32Float,
+        format: wgpu::TextureFormat::Rgba8Unorm,
         usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
         view_formats: &[],
     });
 
-    queue.write_texture(
-        wgpu::TexelCopyTextureInfoBase {
-            texture: &texture,
-            mip_level: 0,
-            origin: wgpu::Origin3d::ZERO,
-            aspect: wgpu::TextureAspect::All,
-        },
-        &raw_data,
-        wgpu::TexelCopyBufferLayout {
-            offset: 0,
-            bytes_per_row: Some(width * std::mem::size_of::<f32>() as u32),
-            rows_per_image: Some(height),
-        },
-        texture_size,
-    );
+    for first_layer in (0..texture.depth_or_array_layers()).step_by(layers as usize) {
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfoBase {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: first_layer,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            data,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(width * 4),
+                rows_per_image: Some(height),
+            },
+            texture_size,
+        );
+    }
+
+    texture
+}
+
+/// Creates a procedural weather map. The channels have the same meaning as a painted one:
+///
+/// - R: coverage, the probability of clouds existing at this location.
+/// - G: cloud type, 0 for stratus, 0.5 for cumulus and 1 for cumulonimbus.
+/// - B: density multiplier, higher where it is raining.
+///
+/// The map tiles seamlessly, so it can be repeated across an infinite layer of clouds. Each of
+/// the `layers` is a different map, so that different cloud volumes can read different maps.
+pub fn create_weather_map_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    width: u32,
+    height: u32,
+    layers: u32,
+    label: Option<&str>,
+    settings: FbmSettings,
+) -> wgpu::Texture {
+    let size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+
+    let mut data = vec![0; (width * height * layers * 4) as usize];
+
+    for layer in 0..layers {
+        for y in 0..height {
+            for x in 0..width {
+                let point = [x as f64, y as f64, 0.0];
+                let index = (((layer * height + y) * width + x) * 4) as usize;
+
+                for channel in 0..3 {
+                    let seed = settings.seed + layer * 3 + channel;
+                    let noise = |p: [f64; 3], period: [u32; 3]| {
+                        (tileable_perlin(seed, p, period) * 0.5 + 0.5).clamp(0.0, 1.0)
+                    };
+                    // Perlin fBm rarely reaches its extremes, so stretch it to use the full range.
+                    let value = remap(settings.sample(noise, point, size), 0.3, 0.7, 0.0, 1.0);
+                    data[index + channel as usize] = (value.clamp(0.0, 1.0) * 255.0) as u8;
+                }
+                data[index + 3] = 255;
+            }
+        }
+    }
+
+    create_texture_2d_rgba(device, queue, width, height, layers, &data, label)
+}
+
+/// Parameters of a fractal Brownian motion sum of noise octaves.
+#[derive(Debug, Clone, Copy)]
+pub struct FbmSettings {
+    pub seed: u32,
+    /// Frequency of the first octave, in cycles per texel.
+    pub frequency: f64,
+    pub octaves: u32,
+    /// Frequency multiplier between two successive octaves.
+    pub lacunarity: f64,
+    /// Amplitude multiplier between two successive octaves.
+    pub gain: f64,
+}
+
+impl FbmSettings {
+    /// Sums the octaves of `noise` at `point` and normalizes the result to [0, 1].
+    /// `noise` is expected to return values in [0, 1].
+    ///
+    /// Every octave repeats across a texture of `size` texels. The frequency of every octave
+    /// is rounded to a whole number of cycles across the texture, and `noise` gets the point
+    /// in cycles together with that number, which it has to repeat after.
+    fn sample<F: Fn([f64; 3], [u32; 3]) -> f64>(
+        &self,
+        noise: F,
+        point: [f64; 3],
+        size: wgpu::Extent3d,
+    ) -> f64 {
+        let extent = [size.width, size.height, size.depth_or_array_layers].map(f64::from);
+        let mut frequency = self.frequency;
+        let mut amplitude = 1.0;
+        let mut sum = 0.0;
+        let mut amplitude_sum = 0.0;
+
+        for _ in 0..self.octaves {
+            let period = extent.map(|extent| (frequency * extent).round().max(1.0));
+            let octave_point = [0, 1, 2].map(|axis| point[axis] * period[axis] / extent[axis]);
+            sum += amplitude * noise(octave_point, period.map(|period| period as u32));
+            amplitude_sum += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+
+        sum / amplitude_sum
+    }
+}
+
+/// Precision of the float 3D textures. 32-bit float textures can only be sampled with linear
+/// filtering if the device has `FLOAT32_FILTERABLE`, so the filtered ones fall back to 16-bit
+/// floats without it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TexturePrecision {
+    Float32,
+    Float16,
+}
+
+impl TexturePrecision {
+    /// The highest precision that can be filtered with `features`.
+    pub fn filterable(features: wgpu::Features) -> Self {
+        if features.contains(wgpu::Features::FLOAT32_FILTERABLE) {
+            Self::Float32
+        } else {
+            Self::Float16
+        }
+    }
+
+    fn gray_format(self) -> wgpu::TextureFormat {
+        match self {
+            Self::Float32 => wgpu::TextureFormat::R32Float,
+            Self::Float16 => wgpu::TextureFormat::R16Float,
+        }
+    }
+
+    fn rgba_format(self) -> wgpu::TextureFormat {
+        match self {
+            Self::Float32 => wgpu::TextureFormat::Rgba32Float,
+            Self::Float16 => wgpu::TextureFormat::Rgba16Float,
+        }
+    }
 
-    Ok(texture)
+    fn to_bytes(self, data: &[f32]) -> Vec<u8> {
+        match self {
+            Self::Float32 => bytemuck::cast_slice(data).to_vec(),
+            Self::Float16 => data
+                .iter()
+                .flat_map(|&value| half::f16::from_f32(value).to_le_bytes())
+                .collect(),
+        }
+    }
 }
 
-pub fn create_noise_texture_3d(
+/// Creates an Rgba 3D texture for the base shape of the clouds. Every octave repeats across
+/// the texture, so it tiles seamlessly.
+///
+/// - R: Perlin-Worley noise, i.e. Perlin fBm remapped by inverted Worley fBm.
+/// - G, B, A: inverted Worley fBm at 2x, 4x and 8x the base frequency.
+pub fn create_perlin_worley_texture_3d(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     size: wgpu::Extent3d,
+    precision: TexturePrecision,
     label: Option<&str>,
-    seed: u32,
-    frequency: f64,
+    settings: FbmSettings,
 ) -> wgpu::Texture {
-    let generator = Worley::new(seed).set_frequency(frequency);
+    // Get noise in range [-1, 1], and map to [0, 1]
+    let perlin = |p: [f64; 3], period: [u32; 3]| {
+        (tileable_perlin(settings.seed, p, period) * 0.5 + 0.5).clamp(0.0, 1.0)
+    };
+    // Invert the distance so that the cell centers become the dense parts
+    let worley = |p: [f64; 3], period: [u32; 3]| {
+        1.0 - tileable_worley(settings.seed, p, period).clamp(0.0, 1.0)
+    };
+
+    let worley_settings = |frequency_scale: f64| FbmSettings {
+        frequency: settings.frequency * frequency_scale,
+        ..settings
+    };
+
+    let texel_count = (size.width * size.height * size.depth_or_array_layers) as usize;
+    let mut data = vec![0.0; texel_count * 4];
+
+    for z in 0..size.depth_or_array_layers {
+        for y in 0..size.height {
+            for x in 0..size.width {
+                let point = [x as f64, y as f64, z as f64];
+
+                let perlin_fbm = settings.sample(perlin, point, size);
+                let worley_fbm = worley_settings(1.0).sample(worley, point, size);
+                let perlin_worley = remap(perlin_fbm, 0.0, 1.0, worley_fbm, 1.0);
+
+                let index = (z * size.width * size.height + y * size.width + x) as usize * 4;
+                data[index] = perlin_worley as f32;
+                data[index + 1] = worley_settings(2.0).sample(worley, point, size) as f32;
+                data[index + 2] = worley_settings(4.0).sample(worley, point, size) as f32;
+                data[index + 3] = worley_settings(8.0).sample(worley, point, size) as f32;
+            }
+        }
+    }
+
+    create_texture_3d_rgba(device, queue, size, &data, precision, label)
+}
+
+/// Creates a gray 3D texture of inverted Worley fBm, used to erode the edges of the base shape.
+/// Every octave repeats across the texture, so it tiles seamlessly.
+pub fn create_worley_texture_3d(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    size: wgpu::Extent3d,
+    precision: TexturePrecision,
+    label: Option<&str>,
+    settings: FbmSettings,
+) -> wgpu::Texture {
+    let worley = |p: [f64; 3], period: [u32; 3]| {
+        1.0 - tileable_worley(settings.seed, p, period).clamp(0.0, 1.0)
+    };
 
     let mut data = vec![0.0; (size.width * size.height * size.depth_or_array_layers) as usize];
 
     for z in 0..size.depth_or_array_layers {
         for y in 0..size.height {
             for x in 0..size.width {
-                // Get noise in range [-1, 1], and map to [0, 1]
-                let noise = (generator.get([x as f64, y as f64, z as f64]) as f32) * 0.5 + 0.5;
+                let point = [x as f64, y as f64, z as f64];
+                let noise = settings.sample(worley, point, size) as f32;
                 data[(z * size.width * size.height + y * size.width + x) as usize] = noise;
             }
         }
     }
 
-    create_texture_3d_gray(device, queue, size, &data, label)
+    create_texture_3d_gray(device, queue, size, &data, precision, true, label)
+}
+
+/// Gradients of the Perlin noise, the directions to the edges of a cube.
+const PERLIN_GRADIENTS: [[f64; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+/// Perlin noise in about [-1, 1] with one lattice cell per unit, which repeats every `period`
+/// cells along each axis.
+fn tileable_perlin(seed: u32, point: [f64; 3], period: [u32; 3]) -> f64 {
+    let cell = point.map(f64::floor);
+    let local = [0, 1, 2].map(|axis| point[axis] - cell[axis]);
+    let fade = local.map(|t| t * t * t * (t * (t * 6.0 - 15.0) + 10.0));
+
+    let mut value = 0.0;
+    for corner in 0..8 {
+        let offset = [corner & 1, (corner >> 1) & 1, corner >> 2].map(f64::from);
+        let corner_cell = [0, 1, 2].map(|axis| cell[axis] + offset[axis]);
+        let gradient = PERLIN_GRADIENTS[lattice_hash(seed, corner_cell, period) as usize % 12];
+
+        let mut weight = 1.0;
+        let mut dot = 0.0;
+        for axis in 0..3 {
+            weight *= if offset[axis] > 0.0 {
+                fade[axis]
+            } else {
+                1.0 - fade[axis]
+            };
+            dot += gradient[axis] * (local[axis] - offset[axis]);
+        }
+        value += weight * dot;
+    }
+
+    value
+}
+
+/// Distance to the closest feature point, where every unit cell has one at a random position.
+/// The feature points repeat every `period` cells along each axis.
+fn tileable_worley(seed: u32, point: [f64; 3], period: [u32; 3]) -> f64 {
+    let cell = point.map(f64::floor);
+
+    let mut min_distance_squared = f64::MAX;
+    for neighbour in 0..27 {
+        let offset = [neighbour % 3, neighbour / 3 % 3, neighbour / 9].map(|o| f64::from(o) - 1.0);
+        let neighbour_cell = [0, 1, 2].map(|axis| cell[axis] + offset[axis]);
+        let cell_hash = lattice_hash(seed, neighbour_cell, period);
+
+        let mut distance_squared = 0.0;
+        for axis in 0..3 {
+            let jitter = f64::from(hash(cell_hash, axis as u32)) / f64::from(u32::MAX);
+            let delta = neighbour_cell[axis] + jitter - point[axis];
+            distance_squared += delta * delta;
+        }
+        min_distance_squared = min_distance_squared.min(distance_squared);
+    }
+
+    min_distance_squared.sqrt()
+}
+
+/// Hashes a lattice cell, wrapped into `period` so that the lattice repeats.
+fn lattice_hash(seed: u32, cell: [f64; 3], period: [u32; 3]) -> u32 {
+    (0..3).fold(seed, |state, axis| {
+        let wrapped = (cell[axis] as i64).rem_euclid(i64::from(period[axis]));
+        hash(state, wrapped as u32)
+    })
+}
+
+/// Size and seed of a void-and-cluster blue noise texture. With a depth of 1 this is 2D blue
+/// noise. Otherwise every slice is 2D blue noise and every texel is also blue noise along the
+/// depth, so the slices can be used one after another over time (spatio-temporal blue noise).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlueNoiseSettings {
+    pub size: wgpu::Extent3d,
+    pub seed: u32,
+}
+
+/// Creates an R32Float 3D texture of blue noise with values in [0, 1]. Generating it takes a
+/// while, so if `cache_path` is given, the noise is read from there if it was generated with
+/// the same settings before, and written there otherwise.
+pub fn create_blue_noise_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    settings: BlueNoiseSettings,
+    cache_path: Option<&Path>,
+    label: Option<&str>,
+) -> wgpu::Texture {
+    let cached = cache_path.and_then(|path| read_blue_noise_cache(path, settings));
+    let data = cached.unwrap_or_else(|| {
+        let data = generate_blue_noise(settings);
+        if let Some(path) = cache_path
+            && let Err(error) = write_blue_noise_cache(path, settings, &data)
+        {
+            log::warn!(
+                "Failed to cache blue noise to {}: {}",
+                path.display(),
+                error
+            );
+        }
+        data
+    });
+
+    // The blue noise is loaded per texel, so it never has to be filtered and needs no mips.
+    create_texture_3d_gray(
+        device,
+        queue,
+        settings.size,
+        &data,
+        TexturePrecision::Float32,
+        false,
+        label,
+    )
+}
+
+/// Loads a precomputed blue noise mask from a grayscale image, as a 3D texture with a single
+/// slice. It can stand in for [`create_blue_noise_texture`], but without the slices along the
+/// depth, the noise doesn't change over time.
+pub fn load_blue_noise_texture<P: AsRef<Path>>(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    path: &P,
+) -> image::ImageResult<wgpu::Texture> {
+    let image = image::open(path)?.to_luma32f();
+    let (width, height) = image.dimensions();
+
+    let file_name = path
+        .as_ref()
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    Ok(create_texture_3d_gray(
+        device,
+        queue,
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        &image.into_raw(),
+        TexturePrecision::Float32,
+        false,
+        Some(format!("Luma32f (Gray) Texture {}", file_name.as_str()).as_str()),
+    ))
+}
+
+/// The cache is a header of the version, width, height, depth and seed, followed by the values,
+/// all little endian.
+fn read_blue_noise_cache(path: &Path, settings: BlueNoiseSettings) -> Option<Vec<f32>> {
+    let bytes = std::fs::read(path).ok()?;
+    let mut words = bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()));
+
+    let header = blue_noise_cache_header(settings);
+    if !header.iter().all(|&value| words.next() == Some(value)) {
+        return None;
+    }
+
+    let data: Vec<f32> = words.map(f32::from_bits).collect();
+    (data.len() == texel_count(settings.size)).then_some(data)
+}
+
+fn write_blue_noise_cache(
+    path: &Path,
+    settings: BlueNoiseSettings,
+    data: &[f32],
+) -> std::io::Result<()> {
+    let words = blue_noise_cache_header(settings)
+        .into_iter()
+        .chain(data.iter().map(|value| value.to_bits()));
+    let bytes: Vec<u8> = words.flat_map(u32::to_le_bytes).collect();
+    std::fs::write(path, bytes)
+}
+
+/// Has to be increased whenever the generated noise or the layout of the cache changes, so that
+/// old caches are generated again.
+const BLUE_NOISE_CACHE_VERSION: u32 = 1;
+
+fn blue_noise_cache_header(settings: BlueNoiseSettings) -> [u32; 5] {
+    let size = settings.size;
+    [
+        BLUE_NOISE_CACHE_VERSION,
+        size.width,
+        size.height,
+        size.depth_or_array_layers,
+        settings.seed,
+    ]
+}
+
+fn texel_count(size: wgpu::Extent3d) -> usize {
+    (size.width * size.height * size.depth_or_array_layers) as usize
+}
+
+/// Standard deviation of the Gaussian the void-and-cluster energy is filtered with, in texels.
+const BLUE_NOISE_SIGMA: f32 = 1.9;
+
+/// Generates blue noise with the void-and-cluster method, see Ulichney, "The void-and-cluster
+/// method for dither array generation", 1993. Every texel gets a rank in the order it is set
+/// in a binary pattern that stays as evenly spread out as possible, and the ranks are then
+/// normalized to [0, 1].
+fn generate_blue_noise(settings: BlueNoiseSettings) -> Vec<f32> {
+    let count = texel_count(settings.size);
+    let mut pattern = BinaryPattern::new(settings.size);
+
+    // Start from a random pattern with about a tenth of the texels set.
+    for index in 0..count {
+        if hash(settings.seed, index as u32).is_multiple_of(10) {
+            pattern.set(index, true);
+        }
+    }
+    if pattern.ones == 0 {
+        pattern.set(0, true);
+    }
+
+    // Move the texels of the tightest clusters into the largest voids, until the texel
+    // removed from the tightest cluster leaves the largest void itself.
+    for _ in 0..count {
+        let cluster = pattern.tightest_cluster();
+        pattern.set(cluster, false);
+        let void = pattern.largest_void();
+        pattern.set(void, true);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; count];
+
+    // The texels of the initial pattern are ranked in the reverse order of their removal.
+    let mut removing = pattern.clone();
+    while removing.ones > 0 {
+        let cluster = removing.tightest_cluster();
+        removing.set(cluster, false);
+        ranks[cluster] = removing.ones;
+    }
+
+    // The other texels are ranked in the order they fill the largest voids.
+    while pattern.ones < count {
+        let void = pattern.largest_void();
+        ranks[void] = pattern.ones;
+        pattern.set(void, true);
+    }
+
+    ranks
+        .into_iter()
+        .map(|rank| (rank as f32 + 0.5) / count as f32)
+        .collect()
+}
+
+/// A binary pattern on a torus, with the energy of every texel: the sum of a Gaussian
+/// centered at every set texel.
+#[derive(Clone)]
+struct BinaryPattern {
+    size: [usize; 3],
+    values: Vec<bool>,
+    energy: Vec<f32>,
+    ones: usize,
+    /// Offsets and weights of the Gaussian, truncated where it becomes negligible.
+    kernel: Vec<([isize; 3], f32)>,
+}
+
+impl BinaryPattern {
+    fn new(size: wgpu::Extent3d) -> Self {
+        let size = [size.width, size.height, size.depth_or_array_layers].map(|v| v as usize);
+        let count = size.iter().product();
+
+        // Don't let the kernel wrap around onto itself on small sizes.
+        let radius = size.map(|v| ((v as isize - 1) / 2).min((BLUE_NOISE_SIGMA * 3.0) as isize));
+        let mut kernel = Vec::new();
+        for z in -radius[2]..=radius[2] {
+            for y in -radius[1]..=radius[1] {
+                for x in -radius[0]..=radius[0] {
+                    // Only spread the energy within the slice and along the depth, rather than
+                    // over a ball, so that every slice on its own is 2D blue noise.
+                    if z != 0 && (x != 0 || y != 0) {
+                        continue;
+                    }
+                    let distance_squared = (x * x + y * y + z * z) as f32;
+                    let weight =
+                        (-distance_squared / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp();
+                    kernel.push(([x, y, z], weight));
+                }
+            }
+        }
+
+        Self {
+            size,
+            values: vec![false; count],
+            energy: vec![0.0; count],
+            ones: 0,
+            kernel,
+        }
+    }
+
+    fn set(&mut self, index: usize, value: bool) {
+        if self.values[index] == value {
+            return;
+        }
+        self.values[index] = value;
+        self.ones = if value { self.ones + 1 } else { self.ones - 1 };
+
+        let sign = if value { 1.0 } else { -1.0 };
+        let [width, height, _] = self.size;
+        let position = [
+            index % width,
+            index / width % height,
+            index / (width * height),
+        ];
+        for &(offset, weight) in &self.kernel {
+            let [x, y, z] = [0, 1, 2]
+                .map(|i| (position[i] as isize + offset[i]).rem_euclid(self.size[i] as isize));
+            let neighbour = (z as usize * height + y as usize) * width + x as usize;
+            self.energy[neighbour] += sign * weight;
+        }
+    }
+
+    /// The set texel with the most energy.
+    fn tightest_cluster(&self) -> usize {
+        self.extreme_energy(true, |a, b| a > b)
+    }
+
+    /// The unset texel with the least energy.
+    fn largest_void(&self) -> usize {
+        self.extreme_energy(false, |a, b| a < b)
+    }
+
+    fn extreme_energy(&self, value: bool, is_better: fn(f32, f32) -> bool) -> usize {
+        let mut best: Option<usize> = None;
+        for (index, &energy) in self.energy.iter().enumerate() {
+            if self.values[index] == value
+                && best.is_none_or(|best| is_better(energy, self.energy[best]))
+            {
+                best = Some(index);
+            }
+        }
+        best.unwrap()
+    }
 }
 
+/// A small integer hash, see "Hash Functions for GPU Rendering", Jarzynski and Olano, 2020.
+fn hash(seed: u32, value: u32) -> u32 {
+    let state = value
+        .wrapping_add(seed.wrapping_mul(0x9e3779b9))
+        .wrapping_mul(747796405)
+        .wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn remap(value: f64, old_min: f64, old_max: f64, new_min: f64, new_max: f64) -> f64 {
+    new_min + (value - old_min) / (old_max - old_min) * (new_max - new_min)
+}
+
+/// Creates a gray 3D texture, with a full mip chain if `mips` is set.
 pub fn create_texture_3d_gray(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     size: wgpu::Extent3d,
     data: &[f32],
+    precision: TexturePrecision,
+    mips: bool,
+    label: Option<&str>,
+) -> wgpu::Texture {
+    create_texture_3d(
+        device,
+        queue,
+        size,
+        data,
+        1,
+        precision.gray_format(),
+        precision,
+        mips,
+        label,
+    )
+}
+
+pub fn create_texture_3d_rgba(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    size: wgpu::Extent3d,
+    data: &[f32],
+    precision: TexturePrecision,
     label: Option<&str>,
 ) -> wgpu::Texture {
+    create_texture_3d(
+        device,
+        queue,
+        size,
+        data,
+        4,
+        precision.rgba_format(),
+        precision,
+        true,
+        label,
+    )
+}
+
+/// Creates a 3D texture. With `mips`, it gets a full mip chain, where every level is a box
+/// filtered version of the one above it.
+#[allow(clippy::too_many_arguments)]
+fn create_texture_3d(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    size: wgpu::Extent3d,
+    data: &[f32],
+    channels: usize,
+    format: wgpu::TextureFormat,
+    precision: TexturePrecision,
+    mips: bool,
+    label: Option<&str>,
+) -> wgpu::Texture {
+    let mip_level_count = if mips {
+        size.max_mips(wgpu::TextureDimension::D3)
+    } else {
+        1
+    };
     let texture = device.create_texture(&wgpu::TextureDescriptor {
         size,
-        mip_level_count: 1,
+        mip_level_count,
         sample_count: 1,
         dimension: wgpu::TextureDimension::D3,
-        format: wgpu::TextureFormat::R32Float,
+        format,
         usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
         label,
         view_formats: &[],
     });
 
-    queue.write_texture(
-        wgpu::TexelCopyTextureInfo {
-            texture: &texture,
-            mip_level: 0,
-            origin: wgpu::Origin3d::ZERO,
-            aspect: wgpu::TextureAspect::All,
-        },
-        bytemuck::cast_slice(data),
-        wgpu::TexelCopyBufferLayout {
-            offset: 0,
-            bytes_per_row: Some(size.width * std::mem::size_of::<f32>() as u32),
-            rows_per_image: Some(size.height),
-        },
-        size,
-    );
+    let mut level_data = data.to_vec();
+    for mip_level in 0..mip_level_count {
+        let level_size = size.mip_level_size(mip_level, wgpu::TextureDimension::D3);
+        if mip_level > 0 {
+            let parent_size = size.mip_level_size(mip_level - 1, wgpu::TextureDimension::D3);
+            level_data = downsample_3d(&level_data, parent_size, level_size, channels);
+        }
+
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &texture,
+                mip_level,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &precision.to_bytes(&level_data),
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(level_size.width * format.block_copy_size(None).unwrap()),
+                rows_per_image: Some(level_size.height),
+            },
+            level_size,
+        );
+    }
 
     texture
 }
 
+/// Averages every 2x2x2 block of `data`. Odd sizes repeat their last texel.
+fn downsample_3d(
+    data: &[f32],
+    size: wgpu::Extent3d,
+    new_size: wgpu::Extent3d,
+    channels: usize,
+) -> Vec<f32> {
+    let index = |x: u32, y: u32, z: u32, size: wgpu::Extent3d| {
+        (z * size.width * size.height + y * size.width + x) as usize * channels
+    };
+
+    let mut new_data = vec![0.0; texel_count(new_size) * channels];
+    for z in 0..new_size.depth_or_array_layers {
+        for y in 0..new_size.height {
+            for x in 0..new_size.width {
+                let new_index = index(x, y, z, new_size);
+                for (dx, dy, dz) in (0..8).map(|i| (i & 1, (i >> 1) & 1, i >> 2)) {
+                    let source_x = (x * 2 + dx).min(size.width - 1);
+                    let source_y = (y * 2 + dy).min(size.height - 1);
+                    let source_z = (z * 2 + dz).min(size.depth_or_array_layers - 1);
+                    let source_index = index(source_x, source_y, source_z, size);
+                    for channel in 0..channels {
+                        new_data[new_index + channel] += data[source_index + channel] / 8.0;
+                    }
+                }
+            }
+        }
+    }
+
+    new_data
+}
+
+/// Creates a 2D texture that can be rendered to and then sampled by a later pass.
+pub fn create_render_target(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    label: &str,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    })
+}
+
 pub fn create_depth_texture_view(
     device: &wgpu::Device,
     config: &wgpu::SurfaceConfiguration,
@@ -142,7 +844,122 @@ pub fn create_depth_texture_view(
         view_formats: &[],
     };
     let texture = device.create_texture(&desc);
-    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    view
+    fn blue_noise_settings(seed: u32) -> BlueNoiseSettings {
+        BlueNoiseSettings {
+            size: wgpu::Extent3d {
+                width: 8,
+                height: 8,
+                depth_or_array_layers: 4,
+            },
+            seed,
+        }
+    }
+
+    #[test]
+    fn blue_noise_ranks_are_a_permutation() {
+        let settings = blue_noise_settings(0);
+        let data = generate_blue_noise(settings);
+        let count = texel_count(settings.size);
+        assert_eq!(data.len(), count);
+
+        let mut ranks: Vec<usize> = data
+            .iter()
+            .map(|&value| (value * count as f32 - 0.5).round() as usize)
+            .collect();
+        ranks.sort_unstable();
+        assert_eq!(ranks, (0..count).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn blue_noise_cache_round_trips() {
+        let path = std::env::temp_dir().join(format!(
+            "volumetric-cloud-test-blue-noise-{}.bin",
+            std::process::id()
+        ));
+        let settings = blue_noise_settings(3);
+        let data = generate_blue_noise(settings);
+        write_blue_noise_cache(&path, settings, &data).unwrap();
+
+        assert_eq!(read_blue_noise_cache(&path, settings), Some(data));
+        assert_eq!(read_blue_noise_cache(&path, blue_noise_settings(4)), None);
+        let larger = BlueNoiseSettings {
+            size: wgpu::Extent3d {
+                width: 16,
+                ..settings.size
+            },
+            ..settings
+        };
+        assert_eq!(read_blue_noise_cache(&path, larger), None);
+
+        // A cache of another version is rejected, and so is one cut short.
+        let bytes = std::fs::read(&path).unwrap();
+        let mut old_version = bytes.clone();
+        old_version[..4].copy_from_slice(&(BLUE_NOISE_CACHE_VERSION - 1).to_le_bytes());
+        std::fs::write(&path, &old_version).unwrap();
+        assert_eq!(read_blue_noise_cache(&path, settings), None);
+        std::fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        assert_eq!(read_blue_noise_cache(&path, settings), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tileable_noise_repeats_after_its_period() {
+        let period = [4, 5, 6];
+        let points = [[0.3, 1.7, 2.2], [3.9, 0.1, 5.5], [-1.25, 2.5, 0.75]];
+        for point in points {
+            let shifted = [0, 1, 2].map(|axis| point[axis] + f64::from(period[axis]));
+            let perlin = tileable_perlin(7, point, period);
+            let worley = tileable_worley(7, point, period);
+            assert!((perlin - tileable_perlin(7, shifted, period)).abs() < 1e-9);
+            assert!((worley - tileable_worley(7, shifted, period)).abs() < 1e-9);
+
+            // Shifting along a single axis repeats too.
+            let shifted_x = [point[0] - f64::from(period[0]), point[1], point[2]];
+            assert!((perlin - tileable_perlin(7, shifted_x, period)).abs() < 1e-9);
+            assert!((worley - tileable_worley(7, shifted_x, period)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn downsample_averages_blocks() {
+        let size = wgpu::Extent3d {
+            width: 2,
+            height: 2,
+            depth_or_array_layers: 2,
+        };
+        let new_size = wgpu::Extent3d {
+            width: 1,
+            height: 1,
+            depth_or_array_layers: 1,
+        };
+        // Two channels, the second one being twice the first.
+        let data: Vec<f32> = (0..8).flat_map(|i| [i as f32, i as f32 * 2.0]).collect();
+        assert_eq!(downsample_3d(&data, size, new_size, 2), vec![3.5, 7.0]);
+    }
+
+    #[test]
+    fn downsample_repeats_the_last_texel_of_odd_sizes() {
+        let size = wgpu::Extent3d {
+            width: 3,
+            height: 1,
+            depth_or_array_layers: 1,
+        };
+        let new_size = wgpu::Extent3d {
+            width: 2,
+            height: 1,
+            depth_or_array_layers: 1,
+        };
+        assert_eq!(
+            downsample_3d(&[1.0, 3.0, 8.0], size, new_size, 1),
+            vec![2.0, 8.0]
+        );
+    }
 }